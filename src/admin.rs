@@ -0,0 +1,128 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Admin subcommands: manage tokens and database without running server
+
+use base64::Engine;
+use clap::Subcommand;
+
+use crate::config::Config;
+use crate::error::BotError;
+use crate::telegram_bot::{api_type, TelegramBot};
+use crate::{db, outbox};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// run bot and http server (default)
+    Serve,
+    /// manage chat tokens
+    #[command(subcommand)]
+    Tokens(TokensCommand),
+    /// send message to chat
+    Send {
+        #[arg(long)]
+        chat: i64,
+        /// MarkdownV2, HTML or Markdown
+        #[arg(long)]
+        parse_mode: Option<String>,
+        text: String,
+    },
+    /// database maintenance
+    #[command(subcommand)]
+    Db(DbCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokensCommand {
+    /// list tokens (label, first characters, creation date)
+    List {
+        #[arg(long)]
+        chat: Option<i64>,
+    },
+    /// revoke token by value, or tokens of chat (all or one label)
+    Revoke {
+        /// leaked token itself
+        #[arg(long, conflicts_with_all = ["chat", "label"])]
+        token: Option<String>,
+        #[arg(long, required_unless_present = "token")]
+        chat: Option<i64>,
+        /// revoke only token with this label
+        #[arg(long, requires = "chat")]
+        label: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// write consistent copy of database (safe while bot is running)
+    Backup { path: String },
+    /// rebuild database file to reclaim free space
+    Vacuum,
+}
+
+/// run admin command, `Serve` is handled by main
+pub async fn run(command: Command, config: &Config) -> Result<(), BotError> {
+    match command {
+        Command::Serve => {},
+        Command::Tokens(TokensCommand::List { chat }) => {
+            let sessions = db::list_all_sessions(chat).await?;
+            for (chat_id, session) in &sessions {
+                println!("{chat_id}\t{}\t{}...\t{}", session.label, session.token_prefix
+                    , db::format_unix_date(session.created_at));
+            }
+            println!("{} token(s)", sessions.len());
+        },
+        Command::Tokens(TokensCommand::Revoke { token: Some(token), .. }) => {
+            let token = base64::engine::general_purpose::STANDARD_NO_PAD
+                .decode(token.trim().trim_end_matches('='))
+                .map_err(|_| BotError::Config("token is not valid base64".to_string()))?;
+            match db::delete_session_by_token(&token).await? {
+                None => println!("token not found"),
+                Some((chat_id, label)) => println!("revoked token \"{label}\" of chat {chat_id}"),
+            }
+        },
+        Command::Tokens(TokensCommand::Revoke { chat: Some(chat_id), label, .. }) => {
+            match label {
+                Some(label) => match db::delete_session_by_label(chat_id, &label).await? {
+                    true => println!("revoked token \"{label}\" of chat {chat_id}"),
+                    false => println!("chat {chat_id} has no token \"{label}\""),
+                },
+                None => {
+                    let count = db::list_sessions(chat_id).await?.len();
+                    db::delete_session(chat_id).await?;
+                    println!("revoked {count} token(s) of chat {chat_id}");
+                },
+            }
+        },
+        Command::Tokens(TokensCommand::Revoke { .. }) => unreachable!("clap requires --token or --chat"),
+        Command::Send { chat, parse_mode, text } => {
+            let mut options = api_type::SendMessageOptions::default();
+            if let Some(parse_mode) = parse_mode {
+                options.parse_mode = Some(serde_json::from_value(serde_json::Value::String(parse_mode))
+                    .map_err(|_| BotError::Config("parse_mode should be MarkdownV2, HTML or Markdown".to_string()))?);
+            }
+            TelegramBot::init(config).await?;
+            match outbox::send_message(chat, &text, &options).await? {
+                outbox::Delivery::Sent(messages) => println!("sent {} message(s)", messages.len()),
+                outbox::Delivery::Queued { queued, .. } =>
+                    println!("telegram delivery delayed, {} part(s) queued, running bot will retry", queued.len()),
+                outbox::Delivery::PartlyRejected { sent, error } => {
+                    println!("sent {} message(s), next part rejected by telegram", sent.len());
+                    return Err(error);
+                },
+            }
+        },
+        Command::Db(DbCommand::Backup { path }) => {
+            db::backup(&path).await?;
+            println!("database copied to {path}");
+        },
+        Command::Db(DbCommand::Vacuum) => {
+            db::vacuum().await?;
+            println!("database vacuumed");
+        },
+    }
+
+    Ok(())
+}
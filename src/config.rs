@@ -0,0 +1,348 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Bot configuration.
+//!
+//! Values are taken from TOML file (`--config`, CONFIG_FILE or ./notify-me-bot.toml if exists),
+//! then environment variables (.env file is loaded if present), then command line flags.
+//! Later source overrides earlier one.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use axum::http::HeaderValue;
+use serde::Deserialize;
+
+use crate::error::BotError;
+use crate::logging::LogFormat;
+use crate::telegram_bot::{self, update_mode::UpdateMode, webhook_secret};
+
+/// used when --config and CONFIG_FILE are not set
+const DEFAULT_CONFIG_FILE: &str = "notify-me-bot.toml";
+
+/// one source of settings, not set values are taken from other sources
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    /// telegram bot token from @BotFather [env: TELEGRAM_BOT_TOKEN]
+    #[arg(long)]
+    pub bot_token: Option<String>,
+    /// Bot API server url [env: TELEGRAM_API_URL] [default: https://api.telegram.org]
+    #[arg(long)]
+    pub api_url: Option<String>,
+    /// public https url of webhook [env: TELEGRAM_WEBHOOK]
+    #[arg(long)]
+    pub webhook_url: Option<String>,
+    /// path webhook is served on [env: WEBHOOK_PATH] [default: /webhook]
+    #[arg(long)]
+    pub webhook_path: Option<String>,
+    /// secret telegram sends to webhook [env: TELEGRAM_WEBHOOK_SECRET] [default: random, stored in db]
+    #[arg(long)]
+    pub webhook_secret: Option<String>,
+    /// webhook, polling or auto [env: UPDATE_MODE] [default: auto]
+    #[arg(long)]
+    pub update_mode: Option<String>,
+    /// getUpdates long polling timeout in seconds, 1-50 [env: POLL_TIMEOUT] [default: 50]
+    #[arg(long)]
+    pub poll_timeout: Option<u32>,
+    /// sqlite database file [env: DB_FILE] [default: ./bot.db]
+    #[arg(long)]
+    pub db_file: Option<String>,
+    /// database connection pool size [env: DB_POOL_SIZE] [default: 4]
+    #[arg(long)]
+    pub db_pool_size: Option<u32>,
+    /// address http server listens on [env: LISTEN_ADDR] [default: 127.0.0.1]
+    #[arg(long)]
+    pub listen_addr: Option<String>,
+    /// port http server listens on [env: LISTEN_PORT] [default: 3127]
+    #[arg(long)]
+    pub listen_port: Option<u16>,
+    /// origins allowed to call api from browser, "*" for any [env: CORS_ORIGINS, comma separated] [default: *]
+    #[arg(long, value_delimiter = ',')]
+    pub cors_origins: Option<Vec<String>>,
+    /// /send-file request size limit in bytes [env: MAX_UPLOAD_SIZE] [default: 20971520]
+    #[arg(long)]
+    pub max_upload_size: Option<usize>,
+    /// log output: text or json, level is set by RUST_LOG [env: LOG_FORMAT] [default: text]
+    #[arg(long)]
+    pub log_format: Option<String>,
+}
+
+impl ConfigLayer {
+    pub fn from_file(path: &Path) -> Result<ConfigLayer, BotError> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            BotError::Config(format!("failed read config file {}: {err}", path.display()))
+        })?;
+        Self::from_toml(&text).map_err(|err| match err {
+            BotError::Config(message) => BotError::Config(format!("{}: {message}", path.display())),
+            err => err,
+        })
+    }
+    fn from_toml(text: &str) -> Result<ConfigLayer, BotError> {
+        toml::from_str(text).map_err(|err| BotError::Config(err.to_string()))
+    }
+    pub fn from_env() -> Result<ConfigLayer, BotError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<ConfigLayer, BotError> {
+        let parse = |name: &str| -> Result<Option<u64>, BotError> {
+            match var(name) {
+                None => Ok(None),
+                Some(value) => value.trim().parse().map(Some).map_err(|_| {
+                    BotError::Config(format!("{name}: \"{value}\" is not a number"))
+                }),
+            }
+        };
+        let cors_origins = var("CORS_ORIGINS").map(|origins| {
+            origins.split(',').map(|origin| origin.trim().to_string()).collect()
+        });
+
+        Ok(ConfigLayer {
+            bot_token: var("TELEGRAM_BOT_TOKEN"),
+            api_url: var("TELEGRAM_API_URL"),
+            webhook_url: var("TELEGRAM_WEBHOOK"),
+            webhook_path: var("WEBHOOK_PATH"),
+            webhook_secret: var("TELEGRAM_WEBHOOK_SECRET"),
+            update_mode: var("UPDATE_MODE"),
+            poll_timeout: parse_as(&parse, "POLL_TIMEOUT")?,
+            db_file: var("DB_FILE"),
+            db_pool_size: parse_as(&parse, "DB_POOL_SIZE")?,
+            listen_addr: var("LISTEN_ADDR"),
+            listen_port: parse_as(&parse, "LISTEN_PORT")?,
+            cors_origins,
+            max_upload_size: parse_as(&parse, "MAX_UPLOAD_SIZE")?,
+            log_format: var("LOG_FORMAT"),
+        })
+    }
+    /// values set in `over` replace values of `self`
+    pub fn merge(self, over: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            bot_token: over.bot_token.or(self.bot_token),
+            api_url: over.api_url.or(self.api_url),
+            webhook_url: over.webhook_url.or(self.webhook_url),
+            webhook_path: over.webhook_path.or(self.webhook_path),
+            webhook_secret: over.webhook_secret.or(self.webhook_secret),
+            update_mode: over.update_mode.or(self.update_mode),
+            poll_timeout: over.poll_timeout.or(self.poll_timeout),
+            db_file: over.db_file.or(self.db_file),
+            db_pool_size: over.db_pool_size.or(self.db_pool_size),
+            listen_addr: over.listen_addr.or(self.listen_addr),
+            listen_port: over.listen_port.or(self.listen_port),
+            cors_origins: over.cors_origins.or(self.cors_origins),
+            max_upload_size: over.max_upload_size.or(self.max_upload_size),
+            log_format: over.log_format.or(self.log_format),
+        }
+    }
+}
+
+/// number from env var converted to field type with range check
+fn parse_as<T: TryFrom<u64>>(
+    parse: &impl Fn(&str) -> Result<Option<u64>, BotError>, name: &str
+) -> Result<Option<T>, BotError> {
+    match parse(name)? {
+        None => Ok(None),
+        Some(number) => T::try_from(number).map(Some).map_err(|_| {
+            BotError::Config(format!("{name}: {number} is out of range"))
+        }),
+    }
+}
+
+/// validated configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bot_token: String,
+    pub api_url: String,
+    /// empty in polling mode
+    pub webhook_url: String,
+    pub webhook_path: String,
+    pub webhook_secret: Option<String>,
+    pub update_mode: UpdateMode,
+    pub poll_timeout: u32,
+    pub db_file: String,
+    pub db_pool_size: u32,
+    pub listen: SocketAddr,
+    /// empty list means any origin
+    pub cors_origins: Vec<HeaderValue>,
+    pub max_upload_size: usize,
+    pub log_format: LogFormat,
+}
+
+impl Config {
+    /// read all sources (file, env, command line flags in `cli_layer`)
+    pub fn load(config_file: Option<PathBuf>, cli_layer: ConfigLayer) -> Result<Config, BotError> {
+        let _ = dotenv::dotenv();
+        let config_file = config_file
+            .or_else(|| std::env::var("CONFIG_FILE").ok().map(PathBuf::from))
+            .or_else(|| {
+                let default_file = PathBuf::from(DEFAULT_CONFIG_FILE);
+                default_file.exists().then_some(default_file)
+            });
+        let file_layer = match config_file {
+            None => ConfigLayer::default(),
+            Some(path) => ConfigLayer::from_file(&path)?,
+        };
+        let layer = file_layer.merge(ConfigLayer::from_env()?).merge(cli_layer);
+
+        Config::from_layer(layer)
+    }
+
+    pub fn from_layer(layer: ConfigLayer) -> Result<Config, BotError> {
+        let error = |message: String| Err(BotError::Config(message));
+
+        let bot_token = match layer.bot_token {
+            Some(token) if token.contains(':') => token,
+            Some(_) => return error("bot_token should look like 123456:ABC-DEF (token from @BotFather)".to_string()),
+            None => return error("bot_token is not set (TELEGRAM_BOT_TOKEN, --bot-token or bot_token in config file)".to_string()),
+        };
+        let update_mode: UpdateMode = match layer.update_mode.as_deref().unwrap_or("auto").parse() {
+            Ok(mode) => mode,
+            Err(message) => return error(format!("update_mode: {message}")),
+        };
+        let webhook_url = layer.webhook_url.unwrap_or_default();
+        if update_mode != UpdateMode::Polling && !webhook_url.starts_with("https://") {
+            return error(format!(
+                "webhook_url (TELEGRAM_WEBHOOK) should be https url for update_mode {update_mode:?}, got \"{webhook_url}\""));
+        }
+        let webhook_path = layer.webhook_path.unwrap_or_else(|| "/webhook".to_string());
+        if !webhook_path.starts_with('/') || webhook_path.len() < 2 {
+            return error(format!("webhook_path should start with / , got \"{webhook_path}\""));
+        }
+        if let Some(secret) = &layer.webhook_secret {
+            if !webhook_secret::is_valid_format(secret) {
+                return error("webhook_secret should be 1-256 characters A-Z, a-z, 0-9, _ or -".to_string());
+            }
+        }
+        let api_url = layer.api_url.unwrap_or_else(|| telegram_bot::DEFAULT_API_URL.to_string());
+        if !api_url.starts_with("https://") && !api_url.starts_with("http://") {
+            return error(format!("api_url should be http(s) url, got \"{api_url}\""));
+        }
+
+        let db_pool_size = layer.db_pool_size.unwrap_or(4);
+        if db_pool_size == 0 {
+            return error("db_pool_size should be at least 1".to_string());
+        }
+        let listen_addr = layer.listen_addr.unwrap_or_else(|| "127.0.0.1".to_string());
+        let ip = match IpAddr::from_str(listen_addr.trim_start_matches('[').trim_end_matches(']')) {
+            Ok(ip) => ip,
+            Err(_) => return error(format!("listen_addr: \"{listen_addr}\" is not IP address")),
+        };
+        let listen = SocketAddr::new(ip, layer.listen_port.unwrap_or(3127));
+
+        let mut cors_origins = Vec::new();
+        for origin in layer.cors_origins.unwrap_or_else(|| vec!["*".to_string()]) {
+            if origin == "*" {
+                cors_origins.clear();
+                break;
+            }
+            let valid = origin.starts_with("http://") || origin.starts_with("https://");
+            match HeaderValue::from_str(&origin) {
+                Ok(value) if valid => cors_origins.push(value),
+                _ => return error(format!("cors_origins: \"{origin}\" is not origin like https://example.com")),
+            }
+        }
+        // with 0 poller calls getUpdates in a loop, telegram holds request at most 50 seconds
+        let poll_timeout = layer.poll_timeout.unwrap_or(50);
+        if !(1..=50).contains(&poll_timeout) {
+            return error(format!("poll_timeout: {poll_timeout} should be 1-50 seconds"));
+        }
+        let max_upload_size = layer.max_upload_size.unwrap_or(20 * 1024 * 1024);
+        if max_upload_size == 0 {
+            return error("max_upload_size should be positive".to_string());
+        }
+
+        let log_format: LogFormat = match layer.log_format.as_deref().unwrap_or("text").parse() {
+            Ok(format) => format,
+            Err(message) => return error(format!("log_format: {message}")),
+        };
+
+        Ok(Config {
+            bot_token,
+            api_url,
+            webhook_url,
+            webhook_path,
+            webhook_secret: layer.webhook_secret,
+            update_mode,
+            poll_timeout,
+            db_file: layer.db_file.unwrap_or_else(|| "./bot.db".to_string()),
+            db_pool_size,
+            listen,
+            cors_origins,
+            max_upload_size,
+            log_format,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_error(layer: ConfigLayer) -> String {
+        match Config::from_layer(layer) {
+            Err(BotError::Config(message)) => message,
+            result => panic!("expected config error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let file_layer = ConfigLayer::from_toml(r#"
+            bot_token = "1:file"
+            webhook_url = "https://example.com/hook"
+            listen_port = 8000
+            db_file = "/var/lib/bot.db"
+            cors_origins = ["https://example.com"]
+        "#).unwrap();
+        let env_layer = ConfigLayer::from_vars(|name| match name {
+            "TELEGRAM_BOT_TOKEN" => Some("1:env".to_string()),
+            "LISTEN_PORT" => Some("9000".to_string()),
+            _ => None,
+        }).unwrap();
+        let cli_layer = ConfigLayer { listen_port: Some(9100), ..Default::default() };
+
+        let config = Config::from_layer(file_layer.merge(env_layer).merge(cli_layer)).unwrap();
+        assert_eq!(config.bot_token, "1:env");
+        assert_eq!(config.listen.port(), 9100);
+        assert_eq!(config.db_file, "/var/lib/bot.db");
+        assert_eq!(config.webhook_path, "/webhook");
+        assert_eq!(config.cors_origins, vec![HeaderValue::from_static("https://example.com")]);
+        assert_eq!(config.update_mode, UpdateMode::Auto);
+    }
+
+    #[test]
+    fn test_validation() {
+        let valid = || ConfigLayer {
+            bot_token: Some("1:token".to_string()),
+            webhook_url: Some("https://example.com/webhook".to_string()),
+            ..Default::default()
+        };
+        assert!(Config::from_layer(valid()).is_ok());
+        assert!(config_error(ConfigLayer::default()).contains("bot_token is not set"));
+        assert!(config_error(ConfigLayer { listen_addr: Some("localhost".to_string()), ..valid() })
+            .contains("not IP address"));
+        assert!(config_error(ConfigLayer { update_mode: Some("push".to_string()), ..valid() })
+            .contains("update_mode"));
+        assert!(config_error(ConfigLayer { webhook_url: None, ..valid() }).contains("webhook_url"));
+        assert!(Config::from_layer(ConfigLayer {
+            webhook_url: None, update_mode: Some("polling".to_string()), ..valid()
+        }).is_ok());
+        assert!(config_error(ConfigLayer { cors_origins: Some(vec!["example.com".to_string()]), ..valid() })
+            .contains("cors_origins"));
+        assert_eq!(Config::from_layer(ConfigLayer { listen_addr: Some("[::]".to_string()), ..valid() })
+            .unwrap().listen.to_string(), "[::]:3127");
+
+        let env_error = ConfigLayer::from_vars(|name| {
+            (name == "LISTEN_PORT").then(|| "70000".to_string())
+        }).unwrap_err();
+        assert_eq!(env_error.to_string(), "invalid configuration: LISTEN_PORT: 70000 is out of range");
+        assert!(ConfigLayer::from_toml("unknown_key = 1").is_err());
+        assert!(config_error(ConfigLayer { log_format: Some("xml".to_string()), ..valid() })
+            .contains("log_format"));
+        assert!(config_error(ConfigLayer { poll_timeout: Some(0), ..valid() }).contains("poll_timeout"));
+        assert!(config_error(ConfigLayer { poll_timeout: Some(51), ..valid() }).contains("poll_timeout"));
+        assert_eq!(Config::from_layer(ConfigLayer { poll_timeout: Some(1), ..valid() }).unwrap().poll_timeout, 1);
+    }
+}
@@ -3,7 +3,7 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::{time::{SystemTime, Duration}, str::FromStr, result};
+use std::{time::{SystemTime, Duration}, str::FromStr};
 use sqlx::{
     migrate::MigrateDatabase
     ,sqlite::{
@@ -13,78 +13,203 @@ use sqlx::{
         ,SqliteConnectOptions
     }
 };
+use base64::Engine;
 use once_cell::sync::OnceCell;
 use crate::error::BotError;
 
+mod migrations;
+
 static DB_POOL: OnceCell<SqlitePool> = OnceCell::new();
 
 #[inline]
 fn pool() -> &'static SqlitePool {
     unsafe { DB_POOL.get_unchecked() }
 }
-pub async fn init(db_path: &str) -> Result<(),BotError>
+pub async fn init(db_path: &str, pool_size: u32) -> Result<(),BotError>
 {
     let db_url = format!("sqlite://{}",db_path);
     if !Sqlite::database_exists(&db_url).await? {
-        println!("Creating database {}", db_url);
+        tracing::info!("creating database {}", db_url);
         Sqlite::create_database(&db_url).await?;
-        println!("db created");
     }
     let options = SqliteConnectOptions::from_str(format!("sqlite://{}",db_path).as_str())?
         .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
         .synchronous(sqlx::sqlite::SqliteSynchronous::Normal)
         .foreign_keys(false);
     let db_pool = SqlitePoolOptions::new()
-        .max_connections(4)
+        .max_connections(pool_size)
         .connect_with( options )
-        .await.expect("Failed open Sqlite DB");
+        .await?;
     DB_POOL.set(db_pool).unwrap();
 
-    create_schema().await?;
+    migrations::migrate(pool()).await?;
 
-    return Ok(());
+    Ok(())
+}
+/// wait for running queries and close connections
+pub async fn close() {
+    if let Some(pool) = DB_POOL.get() {
+        pool.close().await;
+    }
 }
-async fn create_schema() -> Result<(), BotError> {
-    let query =
-    "CREATE TABLE IF NOT EXISTS sessions
-    (
-        token         BLOB NOT NULL PRIMARY KEY,
-        chat_id    INTEGER NOT NULL,
-        created_at INTEGER NOT NULL DEFAULT 0
-    );";
-    sqlx::query(query).execute(pool()).await?;
+/// check database answers queries
+pub async fn ping() -> Result<(),BotError>
+{
+    sqlx::query("SELECT 1").execute(pool()).await?;
 
     Ok(())
 }
 /// current timestamp
-fn unix_time_current() -> i64 {
+pub fn unix_time_current() -> i64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs() as i64
 }
 
-pub async fn create_session( token: &[u8], chat_id: i64 ) -> Result<(),BotError>
+/// YYYY-MM-DD (UTC) of unix timestamp
+pub fn format_unix_date(timestamp: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// only digest of token is stored, so leaked db does not expose working tokens
+fn token_digest(token: &[u8]) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, token).as_ref().to_vec()
+}
+const TOKEN_PREFIX_LEN: usize = 6;
+/// start of base64 encoded token, allows user to recognize token without storing it
+pub fn token_prefix(token: &[u8]) -> String {
+    let mut token_str = base64::engine::general_purpose::STANDARD_NO_PAD.encode(token);
+    token_str.truncate(TOKEN_PREFIX_LEN);
+    token_str
+}
+
+/// api token of chat, chat can have many tokens with different labels
+#[derive(Debug)]
+pub struct SessionInfo {
+    pub label: String,
+    pub token_prefix: String,
+    pub created_at: i64,
+}
+
+/// create token with label for chat, replaces existing token with same label
+pub async fn create_session( token: &[u8], chat_id: i64, label: &str ) -> Result<(),BotError>
 {
+    let mut transaction = pool().begin().await?;
     sqlx::query(
         "DELETE FROM sessions \
-        WHERE chat_id = $1"
-    ).bind(chat_id).execute(pool()).await?;
+        WHERE chat_id = $1 AND label = $2"
+    ).bind(chat_id).bind(label).execute(&mut *transaction).await?;
 
     sqlx::query(
-        "INSERT INTO sessions(token, chat_id, created_at)
-        VALUES ($1,$2,$3)")
-        .bind(&token).bind(chat_id).bind(unix_time_current())
-        .execute(pool())
+        "INSERT INTO sessions(token, chat_id, label, token_prefix, created_at)
+        VALUES ($1,$2,$3,$4,$5)")
+        .bind(token_digest(token)).bind(chat_id).bind(label).bind(token_prefix(token))
+        .bind(unix_time_current())
+        .execute(&mut *transaction)
         .await?;
+    transaction.commit().await?;
 
-    return Ok(());
+    Ok(())
 }
 
+/// delete all tokens of chat
 pub async fn delete_session( chat_id: i64 ) -> Result<(),BotError>
 {
     sqlx::query("DELETE FROM sessions WHERE chat_id = $1")
-        .bind(&chat_id).execute(pool())
+        .bind(chat_id).execute(pool())
+        .await?;
+
+    Ok(())
+}
+
+/// delete one token of chat, returns false if label not found
+pub async fn delete_session_by_label( chat_id: i64, label: &str ) -> Result<bool,BotError>
+{
+    let result = sqlx::query("DELETE FROM sessions WHERE chat_id = $1 AND label = $2")
+        .bind(chat_id).bind(label).execute(pool())
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn list_sessions( chat_id: i64 ) -> Result<Vec<SessionInfo>,BotError>
+{
+    let rows = sqlx::query_as::<_,(String,String,i64)>(
+        "SELECT label, token_prefix, created_at
+        FROM    sessions
+        WHERE   chat_id = $1
+        ORDER BY created_at, label"
+    )
+        .bind(chat_id).fetch_all(pool())
+        .await?;
+
+    Ok(rows.into_iter()
+        .map(|(label, token_prefix, created_at)| SessionInfo { label, token_prefix, created_at })
+        .collect())
+}
+
+/// tokens of all chats (or one chat), for admin commands
+pub async fn list_all_sessions( chat_id: Option<i64> ) -> Result<Vec<(i64,SessionInfo)>,BotError>
+{
+    let rows = sqlx::query_as::<_,(i64,String,String,i64)>(
+        "SELECT chat_id, label, token_prefix, created_at
+        FROM    sessions
+        WHERE   $1 IS NULL OR chat_id = $1
+        ORDER BY chat_id, created_at, label"
+    )
+        .bind(chat_id).fetch_all(pool())
+        .await?;
+
+    Ok(rows.into_iter()
+        .map(|(chat_id, label, token_prefix, created_at)| (chat_id, SessionInfo { label, token_prefix, created_at }))
+        .collect())
+}
+
+/// delete token (leaked one), returns chat and label it belonged to
+pub async fn delete_session_by_token( token: &[u8] ) -> Result<Option<(i64,String)>,BotError>
+{
+    let row = sqlx::query_as::<_,(i64,String)>(
+        "DELETE FROM sessions WHERE token = $1 RETURNING chat_id, label")
+        .bind(token_digest(token)).fetch_optional(pool())
+        .await?;
+
+    Ok(row)
+}
+
+/// consistent copy of database to new file
+pub async fn backup( path: &str ) -> Result<(),BotError>
+{
+    sqlx::query("VACUUM INTO $1").bind(path).execute(pool()).await?;
+
+    Ok(())
+}
+pub async fn vacuum() -> Result<(),BotError>
+{
+    sqlx::query("VACUUM").execute(pool()).await?;
+
+    Ok(())
+}
+
+/// group was upgraded to supergroup and got new id
+pub async fn migrate_chat( old_chat_id: i64, new_chat_id: i64 ) -> Result<(),BotError>
+{
+    sqlx::query("UPDATE sessions SET chat_id = $2 WHERE chat_id = $1")
+        .bind(old_chat_id).bind(new_chat_id).execute(pool())
+        .await?;
+    sqlx::query("UPDATE outbox SET chat_id = $2 WHERE chat_id = $1")
+        .bind(old_chat_id).bind(new_chat_id).execute(pool())
         .await?;
 
-    return Ok(());
+    Ok(())
 }
 
 pub async fn find_chat_by_token(token: &[u8] ) -> Result<Option<i64>,BotError>
@@ -94,20 +219,253 @@ pub async fn find_chat_by_token(token: &[u8] ) -> Result<Option<i64>,BotError>
         FROM    sessions
         WHERE   token = $1"
     )
-        .bind(token).fetch_optional(pool())
+        .bind(token_digest(token)).fetch_optional(pool())
         .await?;
 
-    return Ok(row.map( |(id,)| {id} ));
+    Ok(row.map( |(id,)| {id} ))
 }
-pub async fn find_token_by_chat(chat_id: i64 ) -> Result<Option<Vec<u8>>,BotError>
+pub async fn find_session_by_label(chat_id: i64, label: &str ) -> Result<Option<SessionInfo>,BotError>
 {
-    let row = sqlx::query_as::<_,(Vec<u8>,)>(
-        "SELECT token
+    let row = sqlx::query_as::<_,(String,String,i64)>(
+        "SELECT label, token_prefix, created_at
         FROM    sessions
-        WHERE   chat_id = $1"
+        WHERE   chat_id = $1 AND label = $2"
     )
-        .bind(chat_id).fetch_optional(pool())
+        .bind(chat_id).bind(label).fetch_optional(pool())
+        .await?;
+
+    Ok(row.map( |(label, token_prefix, created_at)| SessionInfo { label, token_prefix, created_at } ))
+}
+/// chat of token and secret GitHub/GitLab webhooks of this token are signed with
+pub async fn find_chat_and_secret_by_token(token: &[u8] ) -> Result<Option<(i64,Option<String>)>,BotError>
+{
+    let row = sqlx::query_as::<_,(i64,Option<String>)>(
+        "SELECT chat_id, integration_secret
+        FROM    sessions
+        WHERE   token = $1"
+    )
+        .bind(token_digest(token)).fetch_optional(pool())
+        .await?;
+
+    Ok(row)
+}
+/// returns false if token with label not found
+pub async fn set_integration_secret(chat_id: i64, label: &str, secret: &str ) -> Result<bool,BotError>
+{
+    let result = sqlx::query(
+        "UPDATE sessions SET integration_secret = $3 WHERE chat_id = $1 AND label = $2")
+        .bind(chat_id).bind(label).bind(secret).execute(pool())
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+/// count of tokens and count of chats having tokens
+pub async fn count_sessions() -> Result<(i64,i64),BotError>
+{
+    let row = sqlx::query_as::<_,(i64,i64)>(
+        "SELECT COUNT(*), COUNT(DISTINCT chat_id)
+        FROM    sessions"
+    )
+        .fetch_one(pool())
+        .await?;
+
+    Ok(row)
+}
+
+/// small persistent values of bot (update offset, etc.)
+pub async fn get_bot_state(key: &str) -> Result<Option<String>,BotError>
+{
+    let row = sqlx::query_as::<_,(String,)>("SELECT value FROM bot_state WHERE key = $1")
+        .bind(key).fetch_optional(pool())
         .await?;
 
-    return Ok(row.map( |(token,)| {token} ));
+    Ok(row.map( |(value,)| {value} ))
+}
+pub async fn set_bot_state(key: &str, value: &str) -> Result<(),BotError>
+{
+    sqlx::query(
+        "INSERT INTO bot_state(key, value) VALUES ($1, $2)
+        ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+        .bind(key).bind(value).execute(pool())
+        .await?;
+
+    Ok(())
+}
+/// set several values in one transaction
+pub async fn set_bot_state_values(values: &[(&str, &str)]) -> Result<(),BotError>
+{
+    let mut transaction = pool().begin().await?;
+    for (key, value) in values {
+        sqlx::query(
+            "INSERT INTO bot_state(key, value) VALUES ($1, $2)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+            .bind(key).bind(value).execute(&mut *transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// telegram keeps undelivered updates for 24 hours, older ids will not come again
+const PROCESSED_UPDATES_KEEP_SECS: i64 = 2 * 24 * 3600;
+/// mark update as processed, returns false if it was processed already
+pub async fn claim_update(update_id: i64) -> Result<bool,BotError>
+{
+    let now = unix_time_current();
+    let result = sqlx::query(
+        "INSERT OR IGNORE INTO processed_updates(update_id, processed_at) VALUES ($1, $2)")
+        .bind(update_id).bind(now).execute(pool())
+        .await?;
+    sqlx::query("DELETE FROM processed_updates WHERE processed_at < $1")
+        .bind(now - PROCESSED_UPDATES_KEEP_SECS).execute(pool())
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// message waiting in outbound queue
+#[derive(Debug)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub payload: String,
+    pub attempts: i64,
+}
+
+/// add messages to outbound queue, they will not be picked by sender worker before `not_before`
+pub async fn enqueue_outbox(chat_id: i64, payloads: &[String], not_before: i64) -> Result<Vec<i64>,BotError>
+{
+    let mut ids = Vec::with_capacity(payloads.len());
+    let mut transaction = pool().begin().await?;
+    for payload in payloads {
+        let result = sqlx::query(
+            "INSERT INTO outbox(chat_id, payload, next_attempt_at, created_at)
+            VALUES ($1,$2,$3,$4)")
+            .bind(chat_id).bind(payload).bind(not_before).bind(unix_time_current())
+            .execute(&mut *transaction)
+            .await?;
+        ids.push(result.last_insert_rowid());
+    }
+    transaction.commit().await?;
+
+    Ok(ids)
+}
+
+/// pending messages ready for delivery, only the oldest pending message of every chat
+/// is returned to keep per chat order
+pub async fn fetch_due_outbox(now: i64, limit: i64) -> Result<Vec<OutboxMessage>,BotError>
+{
+    let rows = sqlx::query_as::<_,(i64,i64,String,i64)>(
+        "SELECT id, chat_id, payload, attempts
+        FROM    outbox o
+        WHERE   failed_at IS NULL
+          AND   next_attempt_at <= $1
+          AND   NOT EXISTS (
+                    SELECT 1 FROM outbox p
+                    WHERE  p.chat_id = o.chat_id AND p.id < o.id AND p.failed_at IS NULL)
+        ORDER BY id
+        LIMIT   $2"
+    )
+        .bind(now).bind(limit).fetch_all(pool())
+        .await?;
+
+    Ok(rows.into_iter()
+        .map(|(id, chat_id, payload, attempts)| OutboxMessage { id, chat_id, payload, attempts })
+        .collect())
+}
+
+/// time of the nearest planned delivery attempt
+pub async fn next_outbox_attempt_time() -> Result<Option<i64>,BotError>
+{
+    let row = sqlx::query_as::<_,(Option<i64>,)>(
+        "SELECT MIN(next_attempt_at)
+        FROM    outbox
+        WHERE   failed_at IS NULL"
+    )
+        .fetch_one(pool())
+        .await?;
+
+    Ok(row.0)
+}
+
+/// count of messages waiting for delivery and count of failed ones
+pub async fn count_outbox() -> Result<(i64,i64),BotError>
+{
+    let row = sqlx::query_as::<_,(i64,i64)>(
+        "SELECT COUNT(*) FILTER (WHERE failed_at IS NULL), COUNT(*) FILTER (WHERE failed_at IS NOT NULL)
+        FROM    outbox"
+    )
+        .fetch_one(pool())
+        .await?;
+
+    Ok(row)
+}
+
+/// true if chat has older pending messages than `id`
+pub async fn outbox_has_earlier(chat_id: i64, id: i64) -> Result<bool,BotError>
+{
+    let row = sqlx::query_as::<_,(i64,)>(
+        "SELECT COUNT(*)
+        FROM    outbox
+        WHERE   chat_id = $1 AND id < $2 AND failed_at IS NULL"
+    )
+        .bind(chat_id).bind(id).fetch_one(pool())
+        .await?;
+
+    Ok(row.0 > 0)
+}
+
+pub async fn delete_outbox(id: i64) -> Result<(),BotError>
+{
+    sqlx::query("DELETE FROM outbox WHERE id = $1")
+        .bind(id).execute(pool())
+        .await?;
+
+    Ok(())
+}
+
+pub async fn reschedule_outbox(
+    id: i64, attempts: i64, next_attempt_at: i64, error: &str
+) -> Result<(),BotError>
+{
+    sqlx::query(
+        "UPDATE outbox
+        SET     attempts = $2, next_attempt_at = $3, last_error = $4
+        WHERE   id = $1")
+        .bind(id).bind(attempts).bind(next_attempt_at).bind(error)
+        .execute(pool())
+        .await?;
+
+    Ok(())
+}
+
+/// keep not yet tried messages away from sender worker till `until` (current time hands them
+/// over to worker), messages worker already tried keep their attempts, error and retry time
+pub async fn set_outbox_lease(ids: &[i64], until: i64) -> Result<(),BotError>
+{
+    let mut transaction = pool().begin().await?;
+    for id in ids {
+        sqlx::query("UPDATE outbox SET next_attempt_at = $2 WHERE id = $1 AND attempts = 0")
+            .bind(id).bind(until)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// give up delivery, message stays in table for inspection
+pub async fn fail_outbox(id: i64, attempts: i64, error: &str) -> Result<(),BotError>
+{
+    sqlx::query(
+        "UPDATE outbox
+        SET     attempts = $2, last_error = $3, failed_at = $4
+        WHERE   id = $1")
+        .bind(id).bind(attempts).bind(error).bind(unix_time_current())
+        .execute(pool())
+        .await?;
+
+    Ok(())
 }
@@ -0,0 +1,233 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Versioned schema migrations.
+//!
+//! Applied version is stored in `schema_version` table. Every migration runs in its own
+//! transaction together with version update, so failed migration leaves database on
+//! previous version. Databases created before this table existed have version 0, steps
+//! of first migrations tolerate tables and columns which already exist in such databases.
+
+use sqlx::{SqliteConnection, SqlitePool};
+use crate::error::BotError;
+use super::{token_digest, token_prefix, unix_time_current};
+
+enum Step {
+    Sql(&'static str),
+    /// `ALTER TABLE ADD COLUMN` if column not exists yet
+    AddColumn { table: &'static str, column: &'static str, definition: &'static str },
+    /// replace plaintext tokens in sessions by their digests
+    HashPlaintextTokens,
+}
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    steps: &'static [Step],
+}
+
+/// append new migrations to the end, never change applied ones
+static MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "sessions", steps: &[
+        Step::Sql(
+            "CREATE TABLE IF NOT EXISTS sessions
+            (
+                token         BLOB NOT NULL PRIMARY KEY,
+                chat_id    INTEGER NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT 0
+            );"),
+    ]},
+    Migration { version: 2, description: "outbox", steps: &[
+        Step::Sql(
+            "CREATE TABLE IF NOT EXISTS outbox
+            (
+                id              INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                chat_id         INTEGER NOT NULL,
+                payload         TEXT    NOT NULL,
+                attempts        INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL DEFAULT 0,
+                last_error      TEXT,
+                failed_at       INTEGER,
+                created_at      INTEGER NOT NULL DEFAULT 0
+            );"),
+        Step::Sql("CREATE INDEX IF NOT EXISTS outbox_chat_id ON outbox(chat_id, id);"),
+    ]},
+    Migration { version: 3, description: "named tokens", steps: &[
+        Step::AddColumn { table: "sessions", column: "label", definition: "TEXT NOT NULL DEFAULT 'default'" },
+        Step::Sql("CREATE UNIQUE INDEX IF NOT EXISTS sessions_chat_label ON sessions(chat_id, label);"),
+    ]},
+    Migration { version: 4, description: "hashed tokens", steps: &[
+        Step::HashPlaintextTokens,
+    ]},
+    Migration { version: 5, description: "update offset and processed updates", steps: &[
+        Step::Sql(
+            "CREATE TABLE bot_state
+            (
+                key   TEXT NOT NULL PRIMARY KEY,
+                value TEXT NOT NULL
+            );"),
+        Step::Sql(
+            "CREATE TABLE processed_updates
+            (
+                update_id    INTEGER NOT NULL PRIMARY KEY,
+                processed_at INTEGER NOT NULL
+            );"),
+        Step::Sql("CREATE INDEX processed_updates_time ON processed_updates(processed_at);"),
+    ]},
+    // stored as is: HMAC of GitHub signature can not be checked with hash of secret
+    Migration { version: 6, description: "integration secrets", steps: &[
+        Step::AddColumn { table: "sessions", column: "integration_secret", definition: "TEXT" },
+    ]},
+];
+
+/// schema version supported by this build
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+/// bring database schema to latest version
+pub async fn migrate(pool: &SqlitePool) -> Result<(), BotError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version
+        (
+            version    INTEGER NOT NULL,
+            applied_at INTEGER NOT NULL
+        );")
+        .execute(pool).await?;
+    let current = current_version(pool).await?;
+    if current > latest_version() {
+        return Err(BotError::SchemaTooNew { found: current, supported: latest_version() });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        tracing::info!("applying db migration {} ({})", migration.version, migration.description);
+        let mut transaction = pool.begin().await?;
+        for step in migration.steps {
+            apply_step(&mut transaction, step).await?;
+        }
+        sqlx::query("INSERT INTO schema_version(version, applied_at) VALUES ($1, $2)")
+            .bind(migration.version).bind(unix_time_current())
+            .execute(&mut *transaction).await?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+pub async fn current_version(pool: &SqlitePool) -> Result<i64, BotError> {
+    let row = sqlx::query_as::<_,(Option<i64>,)>("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool).await?;
+
+    Ok(row.0.unwrap_or(0))
+}
+
+async fn apply_step(connection: &mut SqliteConnection, step: &Step) -> Result<(), BotError> {
+    match step {
+        Step::Sql(query) => {
+            sqlx::query(query).execute(&mut *connection).await?;
+        },
+        Step::AddColumn { table, column, definition } => {
+            if !column_exists(connection, table, column).await? {
+                let query = format!("ALTER TABLE {table} ADD COLUMN {column} {definition};");
+                sqlx::query(&query).execute(&mut *connection).await?;
+            }
+        },
+        Step::HashPlaintextTokens => {
+            // token_prefix column is created together with hashing, so if it exists
+            // tokens are hashed already
+            if column_exists(connection, "sessions", "token_prefix").await? {
+                return Ok(());
+            }
+            sqlx::query("ALTER TABLE sessions ADD COLUMN token_prefix TEXT NOT NULL DEFAULT '';")
+                .execute(&mut *connection).await?;
+            let tokens = sqlx::query_as::<_,(Vec<u8>,)>("SELECT token FROM sessions")
+                .fetch_all(&mut *connection).await?;
+            for (token,) in &tokens {
+                sqlx::query("UPDATE sessions SET token = $1, token_prefix = $2 WHERE token = $3")
+                    .bind(token_digest(token)).bind(token_prefix(token)).bind(token)
+                    .execute(&mut *connection).await?;
+            }
+            tracing::info!("hashed {} stored tokens", tokens.len());
+        },
+    }
+
+    Ok(())
+}
+
+async fn column_exists(connection: &mut SqliteConnection, table: &str, column: &str) -> Result<bool, BotError> {
+    let row = sqlx::query_as::<_,(i64,)>(
+        "SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2"
+    )
+        .bind(table).bind(column).fetch_one(&mut *connection)
+        .await?;
+
+    Ok(row.0 > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn memory_pool() -> SqlitePool {
+        // single connection, every connection to :memory: is separate database
+        SqlitePoolOptions::new().max_connections(1)
+            .connect("sqlite::memory:").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_migrate_v0_database() {
+        let pool = memory_pool().await;
+        // schema of first release: sessions only, plaintext tokens
+        sqlx::query(
+            "CREATE TABLE sessions
+            (
+                token         BLOB NOT NULL PRIMARY KEY,
+                chat_id    INTEGER NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT 0
+            );")
+            .execute(&pool).await.unwrap();
+        let token = [7u8; 32];
+        sqlx::query("INSERT INTO sessions(token, chat_id, created_at) VALUES ($1, 42, 1000)")
+            .bind(&token[..]).execute(&pool).await.unwrap();
+
+        migrate(&pool).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+
+        let row = sqlx::query_as::<_,(Vec<u8>,i64,String,String,i64)>(
+            "SELECT token, chat_id, label, token_prefix, created_at FROM sessions")
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(row, (token_digest(&token), 42, "default".to_string(), token_prefix(&token), 1000));
+        let outbox = sqlx::query_as::<_,(i64,)>("SELECT COUNT(*) FROM outbox")
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(outbox.0, 0);
+
+        // second run does nothing (and does not hash tokens twice)
+        migrate(&pool).await.unwrap();
+        let row = sqlx::query_as::<_,(Vec<u8>,)>("SELECT token FROM sessions")
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(row.0, token_digest(&token));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_empty_database() {
+        let pool = memory_pool().await;
+        migrate(&pool).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+        let mut connection = pool.acquire().await.unwrap();
+        assert!(column_exists(&mut connection, "sessions", "token_prefix").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_refuse_newer_database() {
+        let pool = memory_pool().await;
+        migrate(&pool).await.unwrap();
+        sqlx::query("INSERT INTO schema_version(version, applied_at) VALUES ($1, 0)")
+            .bind(latest_version() + 1).execute(&pool).await.unwrap();
+
+        let result = migrate(&pool).await;
+        assert!(matches!(result, Err(BotError::SchemaTooNew { .. })));
+    }
+}
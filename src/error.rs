@@ -26,6 +26,44 @@ pub enum BotError {
     RingError(),
     #[error(transparent)]
     AxumHttpError( #[from] axum::http::Error),
+    #[error("{method} got no answer in {secs}s")]
+    RequestTimeout {
+        method: String,
+        secs: u64,
+    },
+    #[error("database schema version {found} is newer than supported {supported}, update the bot")]
+    SchemaTooNew {
+        found: i64,
+        supported: i64,
+    },
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("update processing queue closed")]
+    UpdateQueueClosed,
+    #[error("update processing queue is full")]
+    UpdateQueueFull,
+    #[error("telegram api error {code}: {description}")]
+    TelegramApi {
+        code: i64,
+        description: String,
+        retry_after: Option<u64>,
+        migrate_to_chat_id: Option<i64>,
+    },
+}
+impl BotError {
+    /// telegram refused request and repeating it will not help
+    /// (bad request parameters, bot blocked by user or removed from chat)
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            BotError::TelegramApi { code, retry_after: None, migrate_to_chat_id: None, .. } =>
+                *code == 400 || *code == 403,
+            _ => false,
+        }
+    }
+    /// telegram rejected bot token, nothing will work until configuration fixed
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, BotError::TelegramApi { code: 401 | 404, .. })
+    }
 }
 // #[derive(thiserror::Error, Debug)]
 // #[error("error with message {msg}")]
@@ -3,26 +3,19 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::{
-    sync::Arc
-    ,time::SystemTime
-};
-use std::sync::Mutex;
-
 use serde::{Deserialize, Serialize};
 use axum::{
     response::IntoResponse
-    ,extract::State
+    ,extract::{multipart::MultipartError, Multipart}
     ,http::{StatusCode,HeaderMap}
     ,Json
 };
 use base64::Engine;
-use crate::{db, state::AppState, telegram_bot};
-use telegram_bot::{api_type, TelegramBot};
+use crate::{db, error::BotError, logging, metrics, outbox, telegram_bot};
+use telegram_bot::{api_type, multipart::FileUpload, update_mode::ModeState, TelegramBot};
 // use serde_json::Value;
 
 pub async fn handle_webhook(
-    State(state): State<Arc<Mutex<AppState>>>,
     headers: HeaderMap
     // ,Json(payload): Json<Value>
     ,Json(api_update): Json<api_type::ApiUpdate>
@@ -31,73 +24,295 @@ pub async fn handle_webhook(
     // conversion errors
     let webhook_token = match headers.get("X-Telegram-Bot-Api-Secret-Token") {
         None => "",
-        Some(token) => match token.to_str() {
-            Ok(token) => token,
-            Err(_) => ""
-        },
+        Some(token) => token.to_str().unwrap_or_default(),
     };
-    println!("got update {api_update:?}");
-    {
-        let current_time = SystemTime::now();
-        let mut state_guard = state.lock().unwrap();
-        if let Ok(n) = current_time.duration_since(state_guard.prev_query_time) {
-            state_guard.prev_query_time = current_time;
-            if n.as_secs() < 5 {
-                println!("delay {}, will switch to polling", n.as_secs());
-                tokio::spawn( async move {
-                    TelegramBot::set_mode_polling().await.unwrap();
-                });
-            }
-        }
-    }
+    tracing::debug!(update_id = api_update.update_id, "webhook update received");
 
-    if let Err(err) = TelegramBot::handle_webhook_update( webhook_token, api_update ).await {
-        println!("handle_webhook got error in TelegramBot::handle_webhook_update: {err:?}");
-        return StatusCode::INTERNAL_SERVER_ERROR;
+    if !TelegramBot::is_webhook_token_valid( webhook_token ) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    // answer telegram right away, slow processing should not make it redeliver updates
+    match TelegramBot::enqueue_update( api_update ) {
+        Ok(()) => {},
+        Err(BotError::UpdateQueueFull) => {
+            // telegram retries update later, slow chat should not hold the request
+            tracing::warn!("handle_webhook: update queue is full, update rejected");
+            return StatusCode::TOO_MANY_REQUESTS;
+        },
+        Err(err) => {
+            tracing::error!("handle_webhook failed to queue update: {err:?}");
+            return StatusCode::SERVICE_UNAVAILABLE;
+        },
     }
 
     StatusCode::OK
 }
 
-pub async fn handle_message(
-    Json(message_request): Json<SendMessageRequest>,
-) -> (StatusCode, Json<QueryResult>) {
-    // db: find user id by token
+pub type ApiResponse = (StatusCode, Json<QueryResult>);
+
+/// readiness fails if database does not answer in time
+const READY_DB_TIMEOUT_SECS: u64 = 2;
+
+/// token bytes from base64 string
+pub fn decode_token(token_str: &str) -> Result<Vec<u8>, ApiResponse> {
     let mut token:[u8;40] = [0;40]; // token size 32 butes, but base64 decode estimates not perfect
-    let token_size = match base64::engine::general_purpose::STANDARD_NO_PAD
-        .decode_slice(&message_request.token,&mut token)
+    match base64::engine::general_purpose::STANDARD_NO_PAD
+        .decode_slice(token_str,&mut token)
     {
         Err(err) => {
-            tracing::error!("Failed decode token string with error {err:?},\n token: \"{}\"", message_request.token);
-            return (StatusCode::BAD_REQUEST
-                    ,Json(QueryResult::error("BAD_REQUEST".to_string(),Some("Failed base64 decode token".to_string()))));
+            tracing::warn!(token = %logging::redact(token_str), "failed decode token: {err:?}");
+            Err((StatusCode::BAD_REQUEST
+                    ,Json(QueryResult::error("BAD_REQUEST".to_string(),Some("Failed base64 decode token".to_string())))))
         },
-        Ok(len) => len,
-    };
-    println!("decoded token size {token_size}");
+        Ok(len) => Ok(token[..len].to_vec()),
+    }
+}
+
+/// find chat connected to api token
+pub async fn authorize(token_str: &str) -> Result<i64, ApiResponse> {
+    // db: find user id by token
+    let token = decode_token(token_str)?;
     // db::add_session(&token[..token_size], 19).await;
-    let chat_id = match db::find_chat_by_token(&token[..token_size]).await {
+    let chat_id = match db::find_chat_by_token(&token).await {
         Err(err) => {
-            tracing::error!("Failed find user by token {err:?},\n token: \"{}\"", message_request.token);
-            return (StatusCode::INTERNAL_SERVER_ERROR
-                    ,Json(QueryResult::error("SERVER_ERROR".to_string(),None)));
+            tracing::error!(token = %logging::redact(token_str), "failed find chat by token: {err:?}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR
+                    ,Json(QueryResult::error("SERVER_ERROR".to_string(),None))));
         },
         Ok(opt) => opt,
     };
     let chat_id = match chat_id {
         None => {
-            return (StatusCode::UNAUTHORIZED
-                    ,Json(QueryResult::error("UNAUTHORIZED".to_string(),Some("token not found".to_string()))));
+            return Err((StatusCode::UNAUTHORIZED
+                    ,Json(QueryResult::error("UNAUTHORIZED".to_string(),Some("token not found".to_string())))));
         },
         Some(chat_id) => chat_id,
     };
-    println!("Found user id {chat_id} for token {}", &message_request.token);
+    tracing::debug!(chat_id, token = %logging::redact(token_str), "request authorized");
 
-    // FIXME: telegram: send message to user
-    TelegramBot::send_message(chat_id, &message_request.message ).await;
+    Ok(chat_id)
+}
 
-    (StatusCode::OK, Json(QueryResult::ok()))
+/// response for error returned while sending to telegram
+fn telegram_error_response(chat_id: i64, err: BotError) -> ApiResponse {
+    match err {
+        BotError::TelegramApi { code, description, .. } => {
+            tracing::warn!("Telegram rejected message for chat {chat_id}: {code} {description}");
+            let (status_code, status) = match code {
+                403 => (StatusCode::FORBIDDEN, "FORBIDDEN"),
+                400 => (StatusCode::BAD_REQUEST, "BAD_REQUEST"),
+                _ => (StatusCode::BAD_GATEWAY, "TELEGRAM_ERROR"),
+            };
+            (status_code, Json(QueryResult::error(status.to_string(), Some(description))))
+        },
+        err => {
+            tracing::error!("Failed send message to chat {chat_id}: {err:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR
+             ,Json(QueryResult::error("SERVER_ERROR".to_string(),None)))
+        },
+    }
+}
+
+/// response with request counted in metrics
+pub fn count_request(endpoint: &str, response: ApiResponse) -> ApiResponse {
+    metrics::inc_counter(metrics::HTTP_REQUESTS, &[("endpoint", endpoint), ("code", response.0.as_str())]);
+    response
+}
+
+pub async fn handle_message(
+    Json(message_request): Json<SendMessageRequest>,
+) -> ApiResponse {
+    count_request("/send-message", send_message(message_request).await)
+}
+async fn send_message(message_request: SendMessageRequest) -> ApiResponse {
+    let chat_id = match authorize(&message_request.token).await {
+        Ok(chat_id) => chat_id,
+        Err(response) => return response,
+    };
+
+    deliver(chat_id, &message_request.message, &message_request.options).await
+}
+
+/// send text through outbox, response tells if it is delivered or queued
+pub async fn deliver(chat_id: i64, text: &str, options: &api_type::SendMessageOptions) -> ApiResponse {
+    match outbox::send_message(chat_id, text, options).await {
+        Err(err) => telegram_error_response(chat_id, err),
+        Ok(outbox::Delivery::Sent(api_messages)) => {
+            let message_ids: Vec<i64> = api_messages.iter().map(|message| message.message_id).collect();
+            tracing::info!("messages {message_ids:?} delivered to chat {chat_id}");
+            let mut result = QueryResult::ok();
+            result.message_ids = Some(message_ids);
+            (StatusCode::OK, Json(result))
+        },
+        Ok(outbox::Delivery::Queued { sent, queued }) => {
+            let mut result = QueryResult::ok();
+            result.message = Some(format!(
+                "telegram delivery delayed, {} message part(s) queued for retry", queued.len()));
+            result.message_ids = Some(sent.iter().map(|message| message.message_id).collect());
+            (StatusCode::ACCEPTED, Json(result))
+        },
+        Ok(outbox::Delivery::PartlyRejected { sent, error }) => {
+            let (status_code, Json(mut result)) = telegram_error_response(chat_id, error);
+            result.message = Some(format!("{} message part(s) delivered, next part rejected by telegram: {}",
+                                          sent.len(), result.message.unwrap_or_default()));
+            result.message_ids = Some(sent.iter().map(|message| message.message_id).collect());
+            (status_code, Json(result))
+        },
+    }
+}
+
+/// multipart form: "token" (first field), "file", optional "caption", "parse_mode",
+/// "disable_notification", "protect_content"
+pub async fn handle_file(
+    multipart: Multipart,
+) -> ApiResponse {
+    count_request("/send-file", send_file(multipart).await)
+}
+async fn send_file(mut multipart: Multipart) -> ApiResponse {
+    // token goes first, upload is not read for unknown clients
+    let chat_id = match multipart.next_field().await {
+        Err(err) => return multipart_error_response(err),
+        Ok(Some(field)) if field.name() == Some("token") => match field.text().await {
+            Err(err) => return multipart_error_response(err),
+            Ok(token) => match authorize(&token).await {
+                Ok(chat_id) => chat_id,
+                Err(response) => return response,
+            },
+        },
+        Ok(_) => return bad_request("token should be the first form field"),
+    };
+    let mut caption: Option<String> = None;
+    let mut options = api_type::SendMessageOptions::default();
+    let mut file: Option<FileUpload> = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return multipart_error_response(err),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            let file_name = field.file_name().unwrap_or("file").to_string();
+            let declared_type = field.content_type().map(str::to_string);
+            let data = match field.bytes().await {
+                Ok(data) => data,
+                Err(err) => return multipart_error_response(err),
+            };
+            let content_type = FileUpload::detect_content_type(&data, declared_type.as_deref());
+            file = Some(FileUpload { file_name, content_type, data: data.to_vec() });
+            continue;
+        }
+        let value = match field.text().await {
+            Ok(value) => value,
+            Err(err) => return multipart_error_response(err),
+        };
+        match name.as_str() {
+            "caption" => caption = Some(value),
+            "parse_mode" => match serde_json::from_value(serde_json::Value::String(value)) {
+                Ok(parse_mode) => options.parse_mode = Some(parse_mode),
+                Err(_) => return bad_request("invalid parse_mode"),
+            },
+            "disable_notification" => options.disable_notification = Some(parse_form_bool(&value)),
+            "protect_content" => options.protect_content = Some(parse_form_bool(&value)),
+            _ => {},
+        }
+    }
+
+    let file = match file {
+        None => return bad_request("file field not found"),
+        Some(file) => file,
+    };
+    if file.data.len() > telegram_bot::MAX_DOCUMENT_SIZE {
+        return (StatusCode::PAYLOAD_TOO_LARGE
+                ,Json(QueryResult::error("PAYLOAD_TOO_LARGE".to_string(), Some("file is too big for telegram".to_string()))));
+    }
+
+    match TelegramBot::send_file(chat_id, &file, caption.as_deref(), &options).await {
+        Err(err) => telegram_error_response(chat_id, err),
+        Ok(api_message) => {
+            tracing::info!("file \"{}\" ({}) delivered to chat {chat_id}", file.file_name, file.content_type);
+            let mut result = QueryResult::ok();
+            result.message_ids = Some(vec![api_message.message_id]);
+            (StatusCode::OK, Json(result))
+        },
+    }
+}
+fn multipart_error_response(err: MultipartError) -> ApiResponse {
+    (err.status(), Json(QueryResult::error("BAD_REQUEST".to_string(), Some(err.body_text()))))
+}
+fn bad_request(message: &str) -> ApiResponse {
+    (StatusCode::BAD_REQUEST, Json(QueryResult::error("BAD_REQUEST".to_string(), Some(message.to_string()))))
+}
+fn parse_form_bool(value: &str) -> bool {
+    matches!(value.trim(), "true" | "1" | "on" | "yes")
 }
+/// liveness probe: process answers http
+pub async fn handle_healthz() -> &'static str {
+    "OK"
+}
+
+/// readiness probe: database answers, bot started and updates are received and processed
+pub async fn handle_readyz() -> (StatusCode, Json<serde_json::Value>) {
+    let database = match tokio::time::timeout(std::time::Duration::from_secs(READY_DB_TIMEOUT_SECS), db::ping()).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(format!("{err}")),
+        Err(_) => Some("query timed out".to_string()),
+    };
+    let telegram = if TelegramBot::is_started() { None } else { Some("bot is not started".to_string()) };
+    let updates = TelegramBot::update_pipeline_problem().await;
+
+    let checks = [("database", database), ("telegram", telegram), ("updates", updates)];
+    let ready = checks.iter().all(|(_, problem)| problem.is_none());
+    let checks: serde_json::Map<String, serde_json::Value> = checks.into_iter()
+        .map(|(name, problem)| {
+            if let Some(problem) = &problem {
+                tracing::warn!("readiness check {name} failed: {problem}");
+            }
+            (name.to_string(), serde_json::Value::String(problem.unwrap_or_else(|| "ok".to_string())))
+        })
+        .collect();
+    let (status_code, status) = if ready {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "NOT_READY")
+    };
+
+    (status_code, Json(serde_json::json!({"status": status, "checks": checks})))
+}
+
+/// prometheus text format
+pub async fn handle_metrics() -> impl IntoResponse {
+    let mut text = metrics::render();
+
+    if let Some(mode) = TelegramBot::mode_state() {
+        let mode_value = |state| if state == mode { 1.0 } else { 0.0 };
+        metrics::render_gauge(&mut text, "notify_me_update_mode", "Current way of receiving updates",
+                              &[(&[("mode", "stopped")], mode_value(ModeState::Stopped)),
+                                (&[("mode", "webhook")], mode_value(ModeState::Webhook)),
+                                (&[("mode", "polling")], mode_value(ModeState::Polling))]);
+    }
+    metrics::render_gauge(&mut text, "notify_me_update_queue_depth", "Received updates waiting for processing",
+                          &[(&[], TelegramBot::update_queue_depth() as f64)]);
+
+    // other metrics are still useful when database is broken
+    match db::count_outbox().await {
+        Ok((outbox_pending, outbox_failed)) => metrics::render_gauge(
+            &mut text, "notify_me_outbox_messages", "Messages in delivery queue by state",
+            &[(&[("state", "pending")], outbox_pending as f64), (&[("state", "failed")], outbox_failed as f64)]),
+        Err(err) => tracing::error!("metrics: failed count outbox messages: {err:?}"),
+    }
+    match db::count_sessions().await {
+        Ok((sessions, chats)) => {
+            metrics::render_gauge(&mut text, "notify_me_sessions", "Active api tokens", &[(&[], sessions as f64)]);
+            metrics::render_gauge(&mut text, "notify_me_chats", "Chats having api tokens", &[(&[], chats as f64)]);
+        },
+        Err(err) => tracing::error!("metrics: failed count sessions: {err:?}"),
+    }
+
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
+#[allow(dead_code)]
 pub async fn handle_options(
     // Json(message_request): Json<SendMessageRequest>,
 
@@ -109,6 +324,10 @@ pub async fn handle_options(
 pub struct SendMessageRequest {
     pub token: String,
     pub message: String,
+    /// parse_mode ("MarkdownV2", "HTML"), disable_notification, disable_web_page_preview,
+    /// protect_content
+    #[serde(flatten)]
+    pub options: api_type::SendMessageOptions,
 }
 
 #[derive(Serialize,Default)]
@@ -116,14 +335,15 @@ pub struct QueryResult {
     pub status: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
+    /// telegram ids of delivered messages (long message is sent in several parts)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_ids: Option<Vec<i64>>,
 }
 impl QueryResult {
-    fn ok() -> QueryResult {
-        let mut result = QueryResult::default();
-        result.status = "OK".to_string();
-        return result;
+    pub fn ok() -> QueryResult {
+        QueryResult { status: "OK".to_string(), ..Default::default() }
     }
-    fn error(status: String, message: Option<String>) -> QueryResult {
-        QueryResult {status, message}
+    pub fn error(status: String, message: Option<String>) -> QueryResult {
+        QueryResult {status, message, message_ids: None}
     }
 }
@@ -0,0 +1,411 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! End to end tests: http api -> bot -> mock Bot API server.
+//!
+//! Bot and database are process wide singletons, so all tests share one environment
+//! running on one runtime. Every test uses own chat id.
+
+use std::net::SocketAddr;
+
+use base64::Engine;
+use hyper::{Body, Request, StatusCode};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+use crate::db;
+use crate::telegram_bot::mock_api::{MockApi, MockError};
+use crate::telegram_bot::multipart::{FileUpload, MultipartForm};
+use crate::config::{Config, ConfigLayer};
+use crate::telegram_bot::TelegramBot;
+
+pub struct TestEnv {
+    pub mock: MockApi,
+    pub url: String,
+}
+
+pub static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap()
+});
+
+static ENV: Lazy<TestEnv> = Lazy::new(|| RUNTIME.block_on(setup()));
+
+pub fn env() -> &'static TestEnv {
+    &ENV
+}
+
+async fn setup() -> TestEnv {
+    let mock = MockApi::start().await;
+
+    let db_path = std::env::temp_dir()
+        .join(format!("notify-me-bot-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db_path);
+    let config = Config::from_layer(ConfigLayer {
+        bot_token: Some("123:TEST".to_string()),
+        api_url: Some(mock.url.clone()),
+        webhook_url: Some("https://example.com/webhook".to_string()),
+        db_file: Some(db_path.to_str().unwrap().to_string()),
+        max_upload_size: Some(1024 * 1024),
+        ..Default::default()
+    }).unwrap();
+    db::init(&config.db_file, config.db_pool_size).await.unwrap();
+    TelegramBot::init(&config).await.unwrap();
+    TelegramBot::start().await.unwrap();
+    crate::outbox::init().unwrap();
+
+    let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(crate::app(&config).into_make_service());
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    TestEnv { mock, url }
+}
+
+/// create session for chat and return token string
+pub async fn create_token(chat_id: i64) -> String {
+    let token = [chat_id as u8; 32];
+    db::create_session(&token, chat_id, "default").await.unwrap();
+    base64::engine::general_purpose::STANDARD_NO_PAD.encode(token)
+}
+
+pub async fn post_json(path: &str, body: Value) -> (StatusCode, Value) {
+    let request = Request::post(format!("{}{path}", env().url))
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+/// post body as is with extra headers
+pub async fn post_raw(path: &str, headers: &[(&str, &str)], body: &str) -> (StatusCode, Value) {
+    let mut request = Request::post(format!("{}{path}", env().url));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let request = request.body(Body::from(body.to_string())).unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[test]
+fn test_send_message_delivered() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(101).await;
+        let (status, body) = post_json("/send-message", json!({"token": token, "message": "build ok"})).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "OK");
+        assert_eq!(body["message_ids"].as_array().unwrap().len(), 1);
+        assert_eq!(env.mock.sent_texts(101), vec!["build ok"]);
+    });
+}
+
+#[test]
+fn test_send_long_message_in_parts() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(106).await;
+        let line = "x".repeat(99) + "\n";
+        let (status, body) = post_json("/send-message", json!({"token": token, "message": line.repeat(100)})).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let texts = env.mock.sent_texts(106);
+        assert_eq!(texts.len(), 3);
+        assert!(texts[0].starts_with("1/3\n"));
+        assert!(texts[2].starts_with("3/3\n"));
+        assert_eq!(body["message_ids"].as_array().unwrap().len(), 3);
+    });
+}
+
+#[test]
+fn test_send_long_message_second_part_rejected() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(115).await;
+        env.mock.fail_chat_after(115, 1, MockError {
+            code: 400, description: "Bad Request: can't parse entities".to_string(), retry_after: None
+        });
+        let line = "x".repeat(99) + "\n";
+        let (status, body) = post_json("/send-message", json!({"token": token, "message": line.repeat(100)})).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message_ids"].as_array().unwrap().len(), 1);
+        assert!(body["message"].as_str().unwrap().starts_with("1 message part(s) delivered"));
+        assert_eq!(env.mock.sent_texts(115).len(), 2);
+        let queued = db::fetch_due_outbox(i64::MAX, 100).await.unwrap();
+        assert!(!queued.iter().any(|message| message.chat_id == 115));
+    });
+}
+
+#[test]
+fn test_send_message_formatting_options() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(105).await;
+        let (status, _) = post_json("/send-message", json!({
+            "token": token, "message": "<b>build</b> ok",
+            "parse_mode": "HTML", "disable_notification": true, "protect_content": true
+        })).await;
+        assert_eq!(status, StatusCode::OK);
+        let params = env.mock.calls("sendMessage").into_iter()
+            .find(|params| params["chat_id"] == 105).unwrap();
+        assert_eq!(params["parse_mode"], "HTML");
+        assert_eq!(params["disable_notification"], true);
+        assert_eq!(params["protect_content"], true);
+        assert!(params.get("disable_web_page_preview").is_none());
+
+        env.mock.fail_chat(105, 1, MockError {
+            code: 400, description: "Bad Request: can't parse entities".to_string(), retry_after: None
+        });
+        let (status, body) = post_json("/send-message", json!({
+            "token": token, "message": "<b>build", "parse_mode": "HTML"
+        })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "Bad Request: can't parse entities");
+    });
+}
+
+async fn post_file(token: &str, file: FileUpload, caption: &str) -> (StatusCode, Value) {
+    let mut form = MultipartForm::new().unwrap();
+    form.add_text("token", token);
+    form.add_text("caption", caption);
+    form.add_file("file", &file);
+    post_form(form).await
+}
+async fn post_form(form: MultipartForm) -> (StatusCode, Value) {
+    let request = Request::post(format!("{}/send-file", env().url))
+        .header("content-type", form.content_type())
+        .body(Body::from(form.into_body()))
+        .unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[test]
+fn test_send_file() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(107).await;
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&[0u8; 100]);
+        let image = FileUpload {
+            file_name: "screenshot.png".to_string(), content_type: "image/png".to_string(), data: png
+        };
+        let (status, body) = post_file(&token, image, "ui test failed").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message_ids"].as_array().unwrap().len(), 1);
+        let photo = env.mock.calls("sendPhoto").into_iter()
+            .find(|params| params["chat_id"] == 107).unwrap();
+        assert_eq!(photo["caption"], "ui test failed");
+        assert_eq!(photo["photo"]["file_name"], "screenshot.png");
+        assert_eq!(photo["photo"]["size"], 108);
+
+        let report = FileUpload {
+            file_name: "report.csv".to_string(), content_type: "text/csv".to_string(), data: b"a,b\n1,2\n".to_vec()
+        };
+        let (status, _) = post_file(&token, report, "report").await;
+        assert_eq!(status, StatusCode::OK);
+        let document = env.mock.calls("sendDocument").into_iter()
+            .find(|params| params["chat_id"] == 107).unwrap();
+        assert_eq!(document["document"]["content_type"], "text/csv");
+
+        let (status, _) = post_file("AAAA", FileUpload {
+            file_name: "x".to_string(), content_type: "text/plain".to_string(), data: b"x".to_vec()
+        }, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let text_file = FileUpload {
+            file_name: "x.txt".to_string(), content_type: "text/plain".to_string(), data: b"x".to_vec()
+        };
+        let mut form = MultipartForm::new().unwrap();
+        form.add_file("file", &text_file);
+        form.add_text("token", &token);
+        let (status, body) = post_form(form).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "token should be the first form field");
+
+        let mut form = MultipartForm::new().unwrap();
+        form.add_text("token", &token);
+        form.add_text("parse_mode", "HTM");
+        form.add_file("file", &text_file);
+        let (status, body) = post_form(form).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "invalid parse_mode");
+
+        let large = FileUpload {
+            file_name: "big.bin".to_string(), content_type: "application/octet-stream".to_string(),
+            data: vec![0u8; 2 * 1024 * 1024]
+        };
+        let (status, _) = post_file(&token, large, "").await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    });
+}
+
+#[test]
+fn test_send_message_unknown_token() {
+    RUNTIME.block_on(async {
+        let token = base64::engine::general_purpose::STANDARD_NO_PAD.encode([0xEEu8; 32]);
+        let (status, body) = post_json("/send-message", json!({"token": token, "message": "x"})).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["status"], "UNAUTHORIZED");
+    });
+}
+
+#[test]
+fn test_send_message_rejected_by_telegram() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(102).await;
+        env.mock.fail_chat(102, 1, MockError {
+            code: 403, description: "Forbidden: bot was blocked by the user".to_string(), retry_after: None
+        });
+        let (status, body) = post_json("/send-message", json!({"token": token, "message": "x"})).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["message"], "Forbidden: bot was blocked by the user");
+    });
+}
+
+#[test]
+fn test_send_message_queued_on_telegram_failure() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(103).await;
+        env.mock.fail_chat(103, 1, MockError {
+            code: 502, description: "Bad Gateway".to_string(), retry_after: None
+        });
+        let (status, body) = post_json("/send-message", json!({"token": token, "message": "later"})).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["status"], "OK");
+        let queued = db::fetch_due_outbox(i64::MAX, 100).await.unwrap();
+        assert!(queued.iter().any(|message| message.chat_id == 103));
+    });
+}
+
+#[test]
+fn test_webhook_rejects_wrong_secret() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let update = json!({
+            "update_id": 2,
+            "message": {"message_id": 1, "date": 0, "chat": {"id": 108}, "text": "/start"}
+        });
+        let request = Request::post(format!("{}/webhook", env.url))
+            .header("content-type", "application/json")
+            .header("X-Telegram-Bot-Api-Secret-Token", "wrong")
+            .body(Body::from(update.to_string()))
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(db::find_session_by_label(108, "default").await.unwrap().is_none());
+    });
+}
+
+#[test]
+fn test_start_command_by_webhook() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let set_webhook = env.mock.wait_calls("setWebhook", 1).await;
+        let secret = set_webhook[0]["secret_token"].as_str().unwrap().to_string();
+
+        let update = json!({
+            "update_id": 1,
+            "message": {"message_id": 1, "date": 0, "chat": {"id": 104}, "text": "/start"}
+        });
+        let request = Request::post(format!("{}/webhook", env.url))
+            .header("content-type", "application/json")
+            .header("X-Telegram-Bot-Api-Secret-Token", secret)
+            .body(Body::from(update.to_string()))
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let texts = env.mock.wait_sent_texts(104, 1).await;
+        assert_eq!(texts.len(), 1);
+        assert!(texts[0].starts_with("generated token"));
+        assert!(db::find_session_by_label(104, "default").await.unwrap().is_some());
+    });
+}
+
+#[test]
+fn test_revoke_leaked_token() {
+    let _env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(109).await;
+        let sessions = db::list_all_sessions(Some(109)).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(token.starts_with(&sessions[0].1.token_prefix));
+
+        let token_bytes = base64::engine::general_purpose::STANDARD_NO_PAD.decode(&token).unwrap();
+        assert_eq!(db::delete_session_by_token(&token_bytes).await.unwrap(), Some((109, "default".to_string())));
+        let (status, _) = post_json("/send-message", json!({"token": token, "message": "leaked"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    });
+}
+
+#[test]
+fn test_metrics() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let token = create_token(110).await;
+        let (status, _) = post_json("/send-message", json!({"token": token, "message": "counted"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(env.mock.sent_texts(110), vec!["counted"]);
+
+        let response = hyper::Client::new()
+            .get(format!("{}/metrics", env.url).parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(text.contains("notify_me_http_requests_total{endpoint=\"/send-message\",code=\"200\"}"));
+        assert!(text.contains("notify_me_telegram_requests_total{method=\"sendMessage\",result=\"ok\"}"));
+        assert!(text.contains("notify_me_telegram_request_duration_seconds_count{method=\"sendMessage\"}"));
+        assert!(text.contains("# TYPE notify_me_update_mode gauge"));
+        assert!(text.contains("notify_me_outbox_messages{state=\"pending\"}"));
+        let sessions = text.lines()
+            .find_map(|line| line.strip_prefix("notify_me_sessions "))
+            .and_then(|value| value.parse::<f64>().ok())
+            .unwrap();
+        assert!(sessions >= 1.0);
+    });
+}
+
+#[test]
+fn test_health_probes() {
+    let env = env();
+    RUNTIME.block_on(async {
+        let client = hyper::Client::new();
+        let request = Request::get(format!("{}/healthz", env.url))
+            .header("x-request-id", "probe-1")
+            .body(Body::empty()).unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-request-id"], "probe-1");
+
+        let response = client.get(format!("{}/readyz", env.url).parse().unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["status"], "OK");
+        assert_eq!(body["checks"]["database"], "ok");
+        assert_eq!(body["checks"]["telegram"], "ok");
+    });
+}
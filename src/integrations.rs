@@ -0,0 +1,440 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Receivers of webhooks sent by other services (Alertmanager, Grafana, GitHub, GitLab),
+//! Slack / Discord compatible incoming webhooks and ntfy style plain text /send/<token>.
+//!
+//! Token is part of url, so service can be pointed to the bot without custom payload.
+//! Payload is rendered to HTML message and delivered like /send-message.
+
+use axum::{
+    body::Bytes
+    ,extract::{Path, Query}
+    ,http::{header, HeaderMap, StatusCode}
+    ,response::{IntoResponse, Response}
+    ,Json
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::db;
+use crate::http_handler::{self, ApiResponse, QueryResult};
+use crate::telegram_bot::api_type::{ParseMode, SendMessageOptions};
+
+pub mod alertmanager;
+mod chat_markup;
+pub mod discord;
+mod git;
+pub mod github;
+pub mod gitlab;
+pub mod grafana;
+pub mod ntfy;
+pub mod slack;
+
+/// request body limit of webhook routes, GitHub and GitLab pushes with file lists are the largest
+pub const MAX_WEBHOOK_SIZE: usize = 1024 * 1024;
+/// request body limit of Slack, Discord and /send routes, text is split to several messages
+/// above 4096 characters anyway
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// text for telegram HTML parse mode
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// link targets telegram accepts, with other ones (relative urls too) whole message is rejected
+pub fn is_url(text: &str) -> bool {
+    text.starts_with("https://") || text.starts_with("http://") || text.starts_with("mailto:")
+}
+
+/// string at json pointer, empty if not found
+pub fn field<'a>(value: &'a Value, pointer: &str) -> &'a str {
+    value.pointer(pointer).and_then(Value::as_str).unwrap_or_default()
+}
+
+/// HTML message, link previews are off unless message should show image
+fn html_options(link_preview: bool) -> SendMessageOptions {
+    SendMessageOptions {
+        parse_mode: Some(ParseMode::HTML),
+        disable_web_page_preview: Some(!link_preview),
+        ..Default::default()
+    }
+}
+
+pub async fn handle_alertmanager(
+    Path(token): Path<String>,
+    body: Bytes,
+) -> ApiResponse {
+    let response = match alertmanager_webhook(&token, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    http_handler::count_request("/integrations/alertmanager", response)
+}
+/// payload is parsed only for known token
+async fn alertmanager_webhook(token: &str, body: &[u8]) -> Result<ApiResponse, ApiResponse> {
+    let chat_id = http_handler::authorize(token).await?;
+    let notification: alertmanager::Notification = parse_payload(body)?;
+    let text = alertmanager::render(&notification);
+
+    Ok(http_handler::deliver(chat_id, &text, &html_options(false)).await)
+}
+
+pub async fn handle_grafana(
+    Path(token): Path<String>,
+    body: Bytes,
+) -> ApiResponse {
+    let response = match grafana_webhook(&token, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    http_handler::count_request("/integrations/grafana", response)
+}
+async fn grafana_webhook(token: &str, body: &[u8]) -> Result<ApiResponse, ApiResponse> {
+    let chat_id = http_handler::authorize(token).await?;
+    let notification: grafana::Notification = parse_payload(body)?;
+    let text = grafana::render(&notification);
+    let options = html_options(grafana::image_url(&notification).is_some());
+
+    Ok(http_handler::deliver(chat_id, &text, &options).await)
+}
+
+/// `?events=push,release` limits events sent to chat
+#[derive(Deserialize, Debug)]
+pub struct EventFilter {
+    events: Option<String>,
+}
+
+impl EventFilter {
+    fn allows(&self, event: &str) -> bool {
+        match &self.events {
+            None => true,
+            Some(events) => events.split(',').any(|allowed| allowed.trim() == event),
+        }
+    }
+}
+
+fn response(status_code: StatusCode, status: &str, message: &str) -> ApiResponse {
+    (status_code, Json(QueryResult::error(status.to_string(), Some(message.to_string()))))
+}
+
+fn ignored(event: &str) -> ApiResponse {
+    let mut result = QueryResult::ok();
+    result.message = Some(format!("event \"{event}\" ignored"));
+    (StatusCode::OK, Json(result))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+    headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default()
+}
+
+/// chat of token and its integration secret
+async fn authorize_with_secret(token: &str) -> Result<(i64, String), ApiResponse> {
+    let token = http_handler::decode_token(token)?;
+    match db::find_chat_and_secret_by_token(&token).await {
+        Err(err) => {
+            tracing::error!("failed find chat by token: {err:?}");
+            Err(response(StatusCode::INTERNAL_SERVER_ERROR, "SERVER_ERROR", "database error"))
+        },
+        Ok(None) => Err(response(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "token not found")),
+        Ok(Some((_, None))) => Err(response(StatusCode::UNAUTHORIZED, "UNAUTHORIZED",
+            "webhook secret of token is not set, create it with /integration_secret in chat with bot")),
+        Ok(Some((chat_id, Some(secret)))) => Ok((chat_id, secret)),
+    }
+}
+
+fn parse_payload<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiResponse> {
+    serde_json::from_slice(body).map_err(|err| {
+        response(StatusCode::BAD_REQUEST, "BAD_REQUEST", &format!("invalid json payload: {err}"))
+    })
+}
+
+pub async fn handle_github(
+    Path(token): Path<String>,
+    Query(filter): Query<EventFilter>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResponse {
+    let response = match github_webhook(&token, &filter, &headers, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    http_handler::count_request("/integrations/github", response)
+}
+async fn github_webhook(
+    token: &str, filter: &EventFilter, headers: &HeaderMap, body: &[u8]
+) -> Result<ApiResponse, ApiResponse> {
+    let (chat_id, secret) = authorize_with_secret(token).await?;
+    if !github::verify_signature(secret.as_bytes(), body, header(headers, github::SIGNATURE_HEADER)) {
+        tracing::warn!("GitHub webhook for chat {chat_id} with wrong signature rejected");
+        return Err(response(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "signature does not match"));
+    }
+    let event = header(headers, github::EVENT_HEADER);
+    if !github::EVENTS.contains(&event) || !filter.allows(event) {
+        return Ok(ignored(event));
+    }
+    let payload = parse_payload(body)?;
+    let Some(text) = github::render(event, &payload) else { return Ok(ignored(event)) };
+
+    Ok(http_handler::deliver(chat_id, &text, &html_options(false)).await)
+}
+
+pub async fn handle_gitlab(
+    Path(token): Path<String>,
+    Query(filter): Query<EventFilter>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResponse {
+    let response = match gitlab_webhook(&token, &filter, &headers, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    http_handler::count_request("/integrations/gitlab", response)
+}
+async fn gitlab_webhook(
+    token: &str, filter: &EventFilter, headers: &HeaderMap, body: &[u8]
+) -> Result<ApiResponse, ApiResponse> {
+    let (chat_id, secret) = authorize_with_secret(token).await?;
+    if !gitlab::verify_token(&secret, header(headers, gitlab::TOKEN_HEADER)) {
+        tracing::warn!("GitLab webhook for chat {chat_id} with wrong token rejected");
+        return Err(response(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "X-Gitlab-Token does not match"));
+    }
+    let payload = parse_payload(body)?;
+    let event = gitlab::event(&payload);
+    if !gitlab::EVENTS.contains(&event) || !filter.allows(event) {
+        return Ok(ignored(event));
+    }
+    let Some(text) = gitlab::render(&payload) else { return Ok(ignored(event)) };
+
+    Ok(http_handler::deliver(chat_id, &text, &html_options(false)).await)
+}
+
+/// Slack answers "ok" as plain text, tools may check it
+fn slack_response(response: ApiResponse) -> Response {
+    let (status_code, Json(result)) = response;
+    if status_code.is_success() {
+        return (StatusCode::OK, "ok").into_response();
+    }
+    (status_code, result.message.unwrap_or(result.status)).into_response()
+}
+
+/// Slack webhook takes json body or form with json in `payload` field
+fn parse_slack_message(headers: &HeaderMap, body: &[u8]) -> Result<slack::Message, ApiResponse> {
+    let is_form = header(headers, header::CONTENT_TYPE.as_str()).starts_with("application/x-www-form-urlencoded");
+    let json = if is_form {
+        let form: Vec<(String, String)> = serde_urlencoded::from_bytes(body).unwrap_or_default();
+        match form.into_iter().find(|(name, _)| name == "payload") {
+            Some((_, payload)) => payload.into_bytes(),
+            None => return Err(response(StatusCode::BAD_REQUEST, "BAD_REQUEST", "form has no payload field")),
+        }
+    } else {
+        body.to_vec()
+    };
+    serde_json::from_slice(&json).map_err(|err| {
+        response(StatusCode::BAD_REQUEST, "BAD_REQUEST", &format!("payload is not slack message: {err}"))
+    })
+}
+
+pub async fn handle_slack(
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let response = match slack_webhook(&token, &headers, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    slack_response(http_handler::count_request("/integrations/slack", response))
+}
+async fn slack_webhook(token: &str, headers: &HeaderMap, body: &[u8]) -> Result<ApiResponse, ApiResponse> {
+    let chat_id = http_handler::authorize(token).await?;
+    let message = parse_slack_message(headers, body)?;
+    let text = slack::render(&message);
+    if text.is_empty() {
+        return Err(response(StatusCode::BAD_REQUEST, "BAD_REQUEST", "no_text"));
+    }
+
+    Ok(http_handler::deliver(chat_id, &text, &html_options(false)).await)
+}
+
+pub async fn handle_discord(
+    Path(token): Path<String>,
+    body: Bytes,
+) -> Response {
+    let response = match discord_webhook(&token, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    let response = http_handler::count_request("/integrations/discord", response);
+    // Discord answers 204 without body (unless ?wait=true, not supported)
+    if response.0.is_success() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    response.into_response()
+}
+async fn discord_webhook(token: &str, body: &[u8]) -> Result<ApiResponse, ApiResponse> {
+    let chat_id = http_handler::authorize(token).await?;
+    let message: discord::Message = parse_payload(body)?;
+    let text = discord::render(&message);
+    if text.is_empty() {
+        return Err(response(StatusCode::BAD_REQUEST, "BAD_REQUEST", "Cannot send an empty message"));
+    }
+
+    Ok(http_handler::deliver(chat_id, &text, &html_options(false)).await)
+}
+
+/// first of option names found in headers (utf-8 allowed) or query
+fn ntfy_option<'a>(headers: &'a HeaderMap, query: &'a HashMap<String, String>, names: &[&str]) -> Option<&'a str> {
+    names.iter()
+        .find_map(|name| headers.get(*name).and_then(|value| std::str::from_utf8(value.as_bytes()).ok()))
+        .or_else(|| names.iter().find_map(|name| query.get(*name).map(String::as_str)))
+}
+
+/// `curl -d "backup done" .../send/<token>`, see [ntfy]
+pub async fn handle_send(
+    Path(token): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResponse {
+    let response = match send_webhook(&token, &query, &headers, &body).await {
+        Ok(response) | Err(response) => response,
+    };
+    http_handler::count_request("/send", response)
+}
+async fn send_webhook(
+    token: &str, query: &HashMap<String, String>, headers: &HeaderMap, body: &[u8]
+) -> Result<ApiResponse, ApiResponse> {
+    let chat_id = http_handler::authorize(token).await?;
+    let Ok(message) = std::str::from_utf8(body) else {
+        return Err(response(StatusCode::BAD_REQUEST, "BAD_REQUEST", "message should be utf-8 text"));
+    };
+    let publish = ntfy::Publish {
+        message,
+        title: ntfy_option(headers, query, ntfy::TITLE_NAMES),
+        priority: ntfy_option(headers, query, ntfy::PRIORITY_NAMES),
+        tags: ntfy_option(headers, query, ntfy::TAGS_NAMES),
+        click: ntfy_option(headers, query, ntfy::CLICK_NAMES),
+    };
+    let (text, options) = ntfy::render(&publish)
+        .map_err(|message| response(StatusCode::BAD_REQUEST, "BAD_REQUEST", &message))?;
+
+    Ok(http_handler::deliver(chat_id, &text, &options).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use crate::db;
+    use crate::integration_tests::{create_token, env, post_json, post_raw, RUNTIME};
+
+    #[test]
+    fn test_alertmanager_webhook() {
+        let env = env();
+        RUNTIME.block_on(async {
+            let token = create_token(111).await;
+            let payload = json!({
+                "status": "firing",
+                "groupLabels": {"alertname": "InstanceDown"},
+                "alerts": [{"status": "firing", "labels": {"alertname": "InstanceDown", "instance": "web-1"},
+                            "annotations": {}, "startsAt": "2023-05-01T11:00:00Z"}]
+            });
+            let (status, _) = post_json(&format!("/integrations/alertmanager/{token}"), payload.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+            let texts = env.mock.sent_texts(111);
+            assert_eq!(texts.len(), 1);
+            assert!(texts[0].starts_with("🔥 <b>FIRING: 1</b> alertname=InstanceDown"));
+
+            let (status, _) = post_json("/integrations/alertmanager/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA", payload).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            // token is checked before payload
+            let (status, _) = post_json("/integrations/alertmanager/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+                                        json!({"alerts": "x"})).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let (status, _) = post_json(&format!("/integrations/alertmanager/{token}"), json!({"alerts": "x"})).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_github_webhook() {
+        let env = env();
+        RUNTIME.block_on(async {
+            let token = create_token(112).await;
+            let path = format!("/integrations/github/{token}");
+            let push = include_str!("integrations/fixtures/github_push.json");
+            let signature = "sha256=53a6c0c9c0e185beba808ba295c5d50aeb44d6a064a17772d2915b95bd6c697b";
+            let headers = [("X-GitHub-Event", "push"), ("X-Hub-Signature-256", signature)];
+
+            let (status, body) = post_raw(&path, &headers, push).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert!(body["message"].as_str().unwrap().contains("/integration_secret"));
+
+            assert!(db::set_integration_secret(112, "default", "fixture-secret").await.unwrap());
+            let (status, _) = post_raw(&path, &headers, push).await;
+            assert_eq!(status, StatusCode::OK);
+            let texts = env.mock.sent_texts(112);
+            assert_eq!(texts.len(), 1);
+            assert!(texts[0].starts_with("📦 octocat pushed 2 commits"));
+
+            let (status, _) = post_raw(&path, &headers, &push.replace("octocat", "attacker")).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            let (status, body) = post_raw(&format!("{path}?events=release"), &headers, push).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["message"], "event \"push\" ignored");
+            assert_eq!(env.mock.sent_texts(112).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_slack_and_discord_webhooks() {
+        let env = env();
+        RUNTIME.block_on(async {
+            let token = create_token(113).await;
+            let (status, _) = post_raw(&format!("/integrations/slack/{token}"),
+                &[("content-type", "application/json")], r#"{"text": "*backup* done"}"#).await;
+            assert_eq!(status, StatusCode::OK);
+            let payload = "payload=%7B%22text%22%3A%22from+form%22%7D";
+            let (status, _) = post_raw(&format!("/integrations/slack/{token}"),
+                &[("content-type", "application/x-www-form-urlencoded")], payload).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = post_json(&format!("/integrations/discord/{token}"),
+                json!({"content": "**deploy** finished"})).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            let (status, _) = post_json(&format!("/integrations/discord/{token}"), json!({"embeds": []})).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            // unknown token is rejected before payload is parsed
+            let (status, _) = post_raw(&format!("/integrations/slack/{}", "A".repeat(43)), &[], "not json").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let (status, _) = post_raw(&format!("/integrations/discord/{}", "A".repeat(43)), &[], "not json").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let large = json!({"content": "x".repeat(super::MAX_MESSAGE_SIZE)});
+            let (status, _) = post_json(&format!("/integrations/discord/{token}"), large).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+            assert_eq!(env.mock.sent_texts(113), vec!["<b>backup</b> done", "from form", "<b>deploy</b> finished"]);
+        });
+    }
+
+    #[test]
+    fn test_send_plain_text() {
+        let env = env();
+        RUNTIME.block_on(async {
+            let token = create_token(114).await;
+            let (status, _) = post_raw(&format!("/send/{token}"), &[], "backup done").await;
+            assert_eq!(status, StatusCode::OK);
+            let headers = [("X-Title", "Backup"), ("X-Priority", "low"), ("X-Tags", "floppy_disk")];
+            let (status, _) = post_raw(&format!("/send/{token}?click=https://backup.example.com"), &headers, "42 GB").await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = post_raw(&format!("/send/{token}"), &[("Priority", "9")], "x").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (status, _) = post_raw(&format!("/send/{}", "A".repeat(43)), &[("Priority", "9")], "x").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let (status, _) = post_raw(&format!("/send/{token}"), &[], &"x".repeat(super::MAX_MESSAGE_SIZE + 1)).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+            assert_eq!(env.mock.sent_texts(114), vec![
+                "backup done", "💾 <b>Backup</b>\n42 GB\n<a href=\"https://backup.example.com\">Open</a>"]);
+        });
+    }
+}
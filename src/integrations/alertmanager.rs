@@ -0,0 +1,213 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Alertmanager webhook payload
+//! (https://prometheus.io/docs/alerting/latest/configuration/#webhook_config).
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::Deserialize;
+
+use super::{escape_html, is_url};
+
+/// annotations shown as alert text, not in annotations list
+const SUMMARY_ANNOTATIONS: &[&str] = &["summary", "description"];
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    /// "firing" or "resolved"
+    pub status: String,
+    #[serde(default)]
+    pub group_labels: BTreeMap<String, String>,
+    #[serde(rename = "externalURL", default)]
+    pub external_url: String,
+    /// alerts not included because of max_alerts limit
+    #[serde(default)]
+    pub truncated_alerts: u64,
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub status: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    pub starts_at: String,
+    /// "0001-01-01T00:00:00Z" while alert is firing
+    #[serde(default)]
+    pub ends_at: String,
+    #[serde(rename = "generatorURL", default)]
+    pub generator_url: String,
+}
+
+/// RFC 3339 time without fractional seconds, `None` for zero time
+fn format_time(time: &str) -> Option<String> {
+    if time.is_empty() || time.starts_with("0001-") {
+        return None;
+    }
+    let (date_time, zone) = time.split_at(time.find(['Z', '+']).unwrap_or(time.len()));
+    let date_time = date_time.split('.').next().unwrap_or_default().replace('T', " ");
+    let zone = if zone == "Z" { " UTC" } else { zone };
+
+    Some(format!("{date_time}{zone}"))
+}
+
+fn format_labels(labels: &BTreeMap<String, String>, skip: &[&str]) -> String {
+    labels.iter()
+        .filter(|(name, _)| !skip.contains(&name.as_str()))
+        .map(|(name, value)| format!("{}={}", escape_html(name), escape_html(value)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn render_alert(out: &mut String, alert: &Alert) {
+    let status = alert.status.to_uppercase();
+    let name = alert.labels.get("alertname").map(String::as_str).unwrap_or("alert");
+    let _ = writeln!(out, "\n<b>[{}] {}</b>", escape_html(&status), escape_html(name));
+    if let Some(summary) = alert.annotations.get("summary") {
+        let _ = writeln!(out, "<i>{}</i>", escape_html(summary));
+    }
+    if let Some(description) = alert.annotations.get("description") {
+        let _ = writeln!(out, "{}", escape_html(description));
+    }
+    let labels = format_labels(&alert.labels, &["alertname"]);
+    if !labels.is_empty() {
+        let _ = writeln!(out, "<b>Labels:</b> {labels}");
+    }
+    for (name, value) in alert.annotations.iter().filter(|(name, _)| !SUMMARY_ANNOTATIONS.contains(&name.as_str())) {
+        let _ = writeln!(out, "<b>{}:</b> {}", escape_html(name), escape_html(value));
+    }
+    match (format_time(&alert.starts_at), format_time(&alert.ends_at)) {
+        (Some(starts), Some(ends)) if alert.status == "resolved" => {
+            let _ = writeln!(out, "Started: {starts}, resolved: {ends}");
+        },
+        (Some(starts), _) => { let _ = writeln!(out, "Started: {starts}"); },
+        _ => {},
+    }
+    if is_url(&alert.generator_url) {
+        let _ = writeln!(out, "<a href=\"{}\">Source</a>", escape_html(&alert.generator_url));
+    }
+}
+
+/// message text (telegram HTML) with firing alerts first
+pub fn render(notification: &Notification) -> String {
+    let firing: Vec<&Alert> = notification.alerts.iter().filter(|alert| alert.status != "resolved").collect();
+    let resolved: Vec<&Alert> = notification.alerts.iter().filter(|alert| alert.status == "resolved").collect();
+
+    let mut out = String::new();
+    let icon = if notification.status == "resolved" { "✅" } else { "🔥" };
+    let mut counts = Vec::new();
+    if !firing.is_empty() {
+        counts.push(format!("FIRING: {}", firing.len()));
+    }
+    if !resolved.is_empty() {
+        counts.push(format!("RESOLVED: {}", resolved.len()));
+    }
+    if counts.is_empty() {
+        counts.push(escape_html(&notification.status.to_uppercase()));
+    }
+    let _ = write!(out, "{icon} <b>{}</b>", counts.join(", "));
+    let group = format_labels(&notification.group_labels, &[]);
+    if !group.is_empty() {
+        let _ = write!(out, " {group}");
+    }
+    out.push('\n');
+
+    for alert in firing.iter().chain(resolved.iter()) {
+        render_alert(&mut out, alert);
+    }
+    if notification.truncated_alerts > 0 {
+        let _ = writeln!(out, "\n…and {} more alerts", notification.truncated_alerts);
+    }
+    if is_url(&notification.external_url) {
+        let _ = writeln!(out, "\n<a href=\"{}\">Alertmanager</a>", escape_html(&notification.external_url));
+    }
+
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let notification: Notification = serde_json::from_value(serde_json::json!({
+            "version": "4",
+            "groupKey": "{}:{alertname=\"DiskFull\"}",
+            "truncatedAlerts": 0,
+            "status": "firing",
+            "receiver": "telegram",
+            "groupLabels": {"alertname": "DiskFull"},
+            "commonLabels": {"alertname": "DiskFull"},
+            "commonAnnotations": {},
+            "externalURL": "http://alertmanager:9093",
+            "alerts": [
+                {
+                    "status": "resolved",
+                    "labels": {"alertname": "DiskFull", "instance": "db-2"},
+                    "annotations": {"summary": "disk is full"},
+                    "startsAt": "2023-05-01T10:00:00.123Z",
+                    "endsAt": "2023-05-01T10:30:00Z",
+                    "generatorURL": "http://prometheus/graph?g0.expr=a&g0.tab=1",
+                    "fingerprint": "2"
+                },
+                {
+                    "status": "firing",
+                    "labels": {"alertname": "DiskFull", "instance": "db-1", "severity": "critical"},
+                    "annotations": {"summary": "disk <90% free", "description": "only 1GB left", "runbook": "wiki"},
+                    "startsAt": "2023-05-01T11:00:00Z",
+                    "endsAt": "0001-01-01T00:00:00Z",
+                    "generatorURL": "http://prometheus/graph",
+                    "fingerprint": "1"
+                }
+            ]
+        })).unwrap();
+
+        assert_eq!(render(&notification), "🔥 <b>FIRING: 1, RESOLVED: 1</b> alertname=DiskFull\n\
+            \n\
+            <b>[FIRING] DiskFull</b>\n\
+            <i>disk &lt;90% free</i>\n\
+            only 1GB left\n\
+            <b>Labels:</b> instance=db-1, severity=critical\n\
+            <b>runbook:</b> wiki\n\
+            Started: 2023-05-01 11:00:00 UTC\n\
+            <a href=\"http://prometheus/graph\">Source</a>\n\
+            \n\
+            <b>[RESOLVED] DiskFull</b>\n\
+            <i>disk is full</i>\n\
+            <b>Labels:</b> instance=db-2\n\
+            Started: 2023-05-01 10:00:00 UTC, resolved: 2023-05-01 10:30:00 UTC\n\
+            <a href=\"http://prometheus/graph?g0.expr=a&amp;g0.tab=1\">Source</a>\n\
+            \n\
+            <a href=\"http://alertmanager:9093\">Alertmanager</a>");
+    }
+
+    #[test]
+    fn test_render_skips_non_http_urls() {
+        let notification: Notification = serde_json::from_value(serde_json::json!({
+            "status": "firing",
+            "externalURL": "/alertmanager",
+            "alerts": [{"status": "firing", "labels": {"alertname": "Down"}, "annotations": {},
+                        "startsAt": "", "generatorURL": "javascript:alert(1)"}]
+        })).unwrap();
+
+        assert_eq!(render(&notification), "🔥 <b>FIRING: 1</b>\n\n<b>[FIRING] Down</b>");
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time("2023-05-01T10:00:00.5+02:00"), Some("2023-05-01 10:00:00+02:00".to_string()));
+        assert_eq!(format_time("0001-01-01T00:00:00Z"), None);
+        assert_eq!(format_time(""), None);
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Slack mrkdwn and Discord markdown converted to telegram HTML.
+//!
+//! Only inline formatting, code and links are converted, unknown syntax stays as text.
+
+use std::borrow::Cow;
+
+use super::{escape_html, is_url};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Dialect {
+    /// *bold* _italic_ ~strike~ <url|text>, text has &amp; &lt; &gt; escaped
+    Slack,
+    /// **bold** *italic* __underline__ ~~strike~~ ||spoiler|| [text](url)
+    Discord,
+}
+
+impl Dialect {
+    /// longer markers first, "**" should not be taken as two "*"
+    fn markers(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Dialect::Slack => &[("*", "b"), ("_", "i"), ("~", "s")],
+            Dialect::Discord => &[("**", "b"), ("__", "u"), ("~~", "s"), ("||", "tg-spoiler"), ("*", "i"), ("_", "i")],
+        }
+    }
+}
+
+pub fn to_html(text: &str, dialect: Dialect) -> String {
+    let mut out = String::new();
+    // ``` blocks, then `code`, both keep text as is
+    for part in split_paired(text, "```") {
+        match part {
+            Part::Code(code) => {
+                let code = if dialect == Dialect::Discord { strip_language(code) } else { code };
+                let code = if dialect == Dialect::Slack { unescape_slack(code) } else { code.to_string() };
+                out.push_str(&format!("<pre>{}</pre>", escape_html(code.trim_matches('\n'))));
+            },
+            Part::Text(text) => {
+                for part in split_paired(text, "`") {
+                    match part {
+                        Part::Code(code) => {
+                            let code = if dialect == Dialect::Slack { unescape_slack(code) } else { code.to_string() };
+                            out.push_str(&format!("<code>{}</code>", escape_html(&code)));
+                        },
+                        Part::Text(text) => out.push_str(&links_to_html(text, dialect)),
+                    }
+                }
+            },
+        }
+    }
+
+    out
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Code(&'a str),
+}
+
+/// text and code between `fence` pairs, not closed fence stays in text
+fn split_paired<'a>(text: &'a str, fence: &str) -> Vec<Part<'a>> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(fence) {
+        let Some(length) = rest[start + fence.len()..].find(fence) else { break };
+        parts.push(Part::Text(&rest[..start]));
+        parts.push(Part::Code(&rest[start + fence.len()..start + fence.len() + length]));
+        rest = &rest[start + fence.len() * 2 + length..];
+    }
+    parts.push(Part::Text(rest));
+
+    parts
+}
+
+/// ```rust\ncode``` -> code
+fn strip_language(code: &str) -> &str {
+    match code.split_once('\n') {
+        Some((first_line, rest)) if !first_line.is_empty()
+            && first_line.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-') => rest,
+        _ => code,
+    }
+}
+
+fn unescape_slack(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+fn link(url: &str, label: &str, dialect: Dialect) -> String {
+    format!("<a href=\"{}\">{}</a>", escape_html(url), emphasis(&escape_html(label), dialect))
+}
+
+/// Slack <url|text>, <!here>, <@U123>, <#C123|general>; Discord [text](url)
+fn links_to_html(text: &str, dialect: Dialect) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    let plain = |out: &mut String, text: &str| {
+        let text = if dialect == Dialect::Slack { unescape_slack(text) } else { text.to_string() };
+        out.push_str(&emphasis(&escape_html(&text), dialect));
+    };
+    loop {
+        let (open, close) = if dialect == Dialect::Slack { ('<', '>') } else { ('[', ')') };
+        let Some(start) = rest.find(open) else { break };
+        let Some(length) = rest[start..].find(close) else { break };
+        let token = &rest[start + 1..start + length];
+        let converted = match dialect {
+            Dialect::Slack => {
+                let (target, label) = token.split_once('|').unwrap_or((token, ""));
+                if is_url(target) {
+                    let label = if label.is_empty() { target } else { label };
+                    Some(link(&unescape_slack(target), &unescape_slack(label), dialect))
+                } else if let Some(mention) = target.strip_prefix('!').or(target.strip_prefix('@')) {
+                    let name = if label.is_empty() { mention } else { label };
+                    Some(escape_html(&format!("@{}", unescape_slack(name))))
+                } else if let Some(channel) = target.strip_prefix('#') {
+                    let name = if label.is_empty() { channel } else { label };
+                    Some(escape_html(&format!("#{}", unescape_slack(name))))
+                } else {
+                    None
+                }
+            },
+            Dialect::Discord => token.split_once("](")
+                .filter(|(label, url)| is_url(url) && !label.contains('['))
+                .map(|(label, url)| link(url, label, dialect)),
+        };
+        match converted {
+            Some(converted) => {
+                plain(&mut out, &rest[..start]);
+                out.push_str(&converted);
+            },
+            None => plain(&mut out, &rest[..start + length + 1]),
+        }
+        rest = &rest[start + length + 1..];
+    }
+    plain(&mut out, rest);
+
+    out
+}
+
+fn is_boundary(c: Option<char>) -> bool {
+    match c {
+        None => true,
+        Some(c) => !c.is_alphanumeric(),
+    }
+}
+
+/// markers around non-empty text on one line, not inside of words
+fn emphasis(text: &str, dialect: Dialect) -> String {
+    text.split('\n').map(|line| emphasis_line(line, dialect)).collect::<Vec<String>>().join("\n")
+}
+
+/// open marker waiting for closing one
+struct OpenMarker {
+    marker: usize,
+    /// index of piece with marker text, replaced by tag when closed
+    piece: usize,
+    /// line position after marker
+    end: usize,
+}
+
+/// one pass over line: open markers wait in stack, closing marker takes the nearest open
+/// one of the same kind, markers opened after it and never closed stay text
+fn emphasis_line(line: &str, dialect: Dialect) -> String {
+    let markers = dialect.markers();
+    let mut pieces: Vec<Cow<str>> = Vec::new();
+    let mut open: Vec<OpenMarker> = Vec::new();
+    // positions in `open` by marker kind, closing marker does not search whole stack
+    let mut open_by_marker: Vec<Vec<usize>> = vec![Vec::new(); markers.len()];
+    let mut text_start = 0;
+    let mut position = 0;
+    while let Some(c) = line[position..].chars().next() {
+        let rest = &line[position..];
+        let Some(marker) = markers.iter().position(|(marker, _)| rest.starts_with(marker)) else {
+            position += c.len_utf8();
+            continue;
+        };
+        let (marker_text, tag) = markers[marker];
+        let before = line[..position].chars().next_back();
+        let after = rest[marker_text.len()..].chars().next();
+        let opener = open_by_marker[marker].last().copied()
+            .filter(|opener| open[*opener].end < position);
+        match opener {
+            Some(opener) if before.is_some_and(|c| !c.is_whitespace()) && is_boundary(after) => {
+                pieces.push(Cow::Borrowed(&line[text_start..position]));
+                pieces[open[opener].piece] = Cow::Owned(format!("<{tag}>"));
+                pieces.push(Cow::Owned(format!("</{tag}>")));
+                for closed in open.drain(opener..) {
+                    open_by_marker[closed.marker].pop();
+                }
+            },
+            _ if is_boundary(before) && after.is_some_and(|c| !c.is_whitespace()) => {
+                pieces.push(Cow::Borrowed(&line[text_start..position]));
+                open_by_marker[marker].push(open.len());
+                open.push(OpenMarker { marker, piece: pieces.len(), end: position + marker_text.len() });
+                pieces.push(Cow::Borrowed(marker_text));
+            },
+            _ => {
+                position += marker_text.len();
+                continue;
+            },
+        }
+        position += marker_text.len();
+        text_start = position;
+    }
+    pieces.push(Cow::Borrowed(&line[text_start..]));
+
+    pieces.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slack() {
+        assert_eq!(to_html("*Deploy* _done_ ~old~ in <https://ci.example.com/1|job &lt;1&gt;> for <!here>", Dialect::Slack),
+            "<b>Deploy</b> <i>done</i> <s>old</s> in <a href=\"https://ci.example.com/1\">job &lt;1&gt;</a> for @here");
+        assert_eq!(to_html("snake_case_name 2*3*4 a < b", Dialect::Slack), "snake_case_name 2*3*4 a &lt; b");
+        assert_eq!(to_html("run `make *all*` and\n```\nx <y>\n```", Dialect::Slack),
+            "run <code>make *all*</code> and\n<pre>x &lt;y&gt;</pre>");
+        assert_eq!(to_html("<@U123> in <#C1|general>, <https://a.example>", Dialect::Slack),
+            "@U123 in #general, <a href=\"https://a.example\">https://a.example</a>");
+    }
+
+    #[test]
+    fn test_discord() {
+        assert_eq!(to_html("**Build** *passed* __now__ ~~maybe~~ ||secret|| [logs](https://ci.example.com)", Dialect::Discord),
+            "<b>Build</b> <i>passed</i> <u>now</u> <s>maybe</s> <tg-spoiler>secret</tg-spoiler> \
+            <a href=\"https://ci.example.com\">logs</a>");
+        assert_eq!(to_html("```rust\nfn main() {}\n``` [x](javascript:alert) <b>", Dialect::Discord),
+            "<pre>fn main() {}</pre> [x](javascript:alert) &lt;b&gt;");
+        assert_eq!(to_html("**not closed", Dialect::Discord), "**not closed");
+        assert_eq!(to_html("*a _b* c_ **", Dialect::Discord), "<i>a _b</i> c_ **");
+    }
+
+    #[test]
+    fn test_emphasis_is_linear() {
+        // unclosed markers used to rescan rest of line for every marker
+        let text = "*a ".repeat(100_000) + &"_b ".repeat(100_000);
+        let started = std::time::Instant::now();
+        assert_eq!(emphasis(&text, Dialect::Slack), text);
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    }
+}
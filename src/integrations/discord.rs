@@ -0,0 +1,120 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Discord webhook payload (https://discord.com/developers/docs/resources/webhook#execute-webhook):
+//! content and embeds.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::chat_markup::{to_html, Dialect};
+use super::{escape_html, field, is_url};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Message {
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub embeds: Vec<Value>,
+}
+
+/// url of embed field, empty if it is not http(s)
+fn url<'a>(embed: &'a Value, pointer: &str) -> &'a str {
+    Some(field(embed, pointer)).filter(|url| is_url(url)).unwrap_or_default()
+}
+
+fn render_embed(embed: &Value) -> String {
+    let mut lines = Vec::new();
+    let markdown = |name: &str| to_html(field(embed, name), Dialect::Discord);
+    match (field(embed, "/author/name"), url(embed, "/author/url")) {
+        ("", _) => {},
+        (name, "") => lines.push(format!("<i>{}</i>", escape_html(name))),
+        (name, url) => lines.push(format!("<i><a href=\"{}\">{}</a></i>", escape_html(url), escape_html(name))),
+    }
+    match (field(embed, "/title"), url(embed, "/url")) {
+        ("", _) => {},
+        (_, "") => lines.push(format!("<b>{}</b>", markdown("/title"))),
+        (title, url) => lines.push(format!("<b><a href=\"{}\">{}</a></b>", escape_html(url), escape_html(title))),
+    }
+    if !field(embed, "/description").is_empty() {
+        lines.push(markdown("/description"));
+    }
+    for item in embed["fields"].as_array().into_iter().flatten() {
+        let name = to_html(field(item, "/name"), Dialect::Discord);
+        let value = to_html(field(item, "/value"), Dialect::Discord);
+        // inline fields are short, block ones can have several lines
+        if item["inline"].as_bool().unwrap_or(false) {
+            lines.push(format!("<b>{name}</b>: {value}"));
+        } else {
+            lines.push(format!("<b>{name}</b>\n{value}"));
+        }
+    }
+    if !url(embed, "/image/url").is_empty() {
+        lines.push(format!("<a href=\"{}\">image</a>", escape_html(url(embed, "/image/url"))));
+    }
+    let footer: Vec<&str> = [field(embed, "/footer/text"), field(embed, "/timestamp")].into_iter()
+        .filter(|text| !text.is_empty())
+        .collect();
+    if !footer.is_empty() {
+        lines.push(format!("<i>{}</i>", escape_html(&footer.join(" · "))));
+    }
+
+    lines.join("\n")
+}
+
+/// message text (telegram HTML), content first, then embeds
+pub fn render(message: &Message) -> String {
+    let mut parts = vec![to_html(&message.content, Dialect::Discord)];
+    parts.extend(message.embeds.iter().map(render_embed));
+    parts.retain(|part| !part.trim().is_empty());
+
+    parts.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "username": "Uptime",
+            "content": "**site down** <@&123>",
+            "embeds": [{
+                "title": "example.com",
+                "url": "https://example.com",
+                "description": "HTTP 502 for *3 minutes*",
+                "color": 15158332,
+                "fields": [
+                    {"name": "Region", "value": "eu-west", "inline": true},
+                    {"name": "Details", "value": "`upstream timeout`"}
+                ],
+                "footer": {"text": "monitor"},
+                "timestamp": "2023-05-01T11:00:00Z"
+            }]
+        })).unwrap();
+
+        assert_eq!(render(&message), "<b>site down</b> &lt;@&amp;123&gt;\n\n\
+            <b><a href=\"https://example.com\">example.com</a></b>\n\
+            HTTP 502 for <i>3 minutes</i>\n\
+            <b>Region</b>: eu-west\n\
+            <b>Details</b>\n<code>upstream timeout</code>\n\
+            <i>monitor · 2023-05-01T11:00:00Z</i>");
+    }
+
+    #[test]
+    fn test_render_drops_non_http_urls() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "embeds": [{
+                "author": {"name": "ci", "url": "javascript:alert(1)"},
+                "title": "build",
+                "url": "discord://build",
+                "image": {"url": "data:image/png;base64,AAAA"}
+            }]
+        })).unwrap();
+
+        assert_eq!(render(&message), "<i>ci</i>\n<b>build</b>");
+    }
+}
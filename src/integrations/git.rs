@@ -0,0 +1,101 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Parts of GitHub and GitLab event summaries, both services describe push the same way.
+
+use std::fmt::Write;
+
+use super::escape_html;
+
+/// commits listed in push summary, rest is counted
+const MAX_COMMITS: usize = 5;
+
+pub struct Commit<'a> {
+    pub id: &'a str,
+    pub message: &'a str,
+    pub url: &'a str,
+    pub author: &'a str,
+}
+
+pub struct Push<'a> {
+    pub user: &'a str,
+    pub repo: &'a str,
+    pub repo_url: &'a str,
+    /// refs/heads/<branch> or refs/tags/<tag>
+    pub git_ref: &'a str,
+    pub created: bool,
+    pub deleted: bool,
+    pub forced: bool,
+    pub commits: Vec<Commit<'a>>,
+    /// can be bigger than `commits.len()`, services send only last commits
+    pub total_commits: usize,
+    pub compare_url: &'a str,
+}
+
+pub fn repo_link(name: &str, url: &str) -> String {
+    if url.is_empty() {
+        return format!("<b>{}</b>", escape_html(name));
+    }
+    format!("<a href=\"{}\"><b>{}</b></a>", escape_html(url), escape_html(name))
+}
+
+pub fn link(text: &str, url: &str) -> String {
+    if url.is_empty() {
+        return escape_html(text);
+    }
+    format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(text))
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 { format!("1 {word}") } else { format!("{count} {word}s") }
+}
+
+pub fn render_push(push: &Push) -> String {
+    let user = escape_html(push.user);
+    let repo = repo_link(push.repo, push.repo_url);
+    if let Some(tag) = push.git_ref.strip_prefix("refs/tags/") {
+        let tag = escape_html(tag);
+        if push.deleted {
+            return format!("🗑 {user} deleted tag <code>{tag}</code> in {repo}");
+        }
+        return format!("🏷 {user} pushed tag <code>{tag}</code> to {repo}");
+    }
+    let branch = escape_html(push.git_ref.strip_prefix("refs/heads/").unwrap_or(push.git_ref));
+    if push.deleted {
+        return format!("🗑 {user} deleted branch <code>{branch}</code> in {repo}");
+    }
+    if push.created && push.total_commits == 0 {
+        return format!("🌱 {user} created branch <code>{branch}</code> in {repo}");
+    }
+
+    let forced = if push.forced { " (force)" } else { "" };
+    let mut out = format!("📦 {user} pushed {}{forced} to {repo}:<code>{branch}</code>\n",
+                          plural(push.total_commits, "commit"));
+    for commit in push.commits.iter().take(MAX_COMMITS) {
+        let short_id: String = commit.id.chars().take(7).collect();
+        let title = commit.message.lines().next().unwrap_or_default();
+        let _ = writeln!(out, "• <a href=\"{}\"><code>{short_id}</code></a> {} — {}",
+                         escape_html(commit.url), escape_html(title), escape_html(commit.author));
+    }
+    let shown = push.commits.len().min(MAX_COMMITS);
+    if push.total_commits > shown {
+        let _ = writeln!(out, "…and {} more", push.total_commits - shown);
+    }
+    if !push.compare_url.is_empty() {
+        let _ = writeln!(out, "{}", link("Compare changes", push.compare_url));
+    }
+
+    out.trim_end().to_string()
+}
+
+/// icon of finished pipeline / workflow
+pub fn result_icon(result: &str) -> &'static str {
+    match result {
+        "success" => "✅",
+        "failure" | "failed" => "❌",
+        "cancelled" | "canceled" | "skipped" => "⚪",
+        _ => "⚠️",
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! GitHub webhooks (https://docs.github.com/en/webhooks/webhook-events-and-payloads).
+//!
+//! Deliveries are signed with HMAC-SHA256 of body in X-Hub-Signature-256 header.
+
+use ring::hmac;
+use serde_json::Value;
+
+use super::field;
+use super::git::{self, Commit, Push};
+
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+/// events rendered to messages, others are ignored
+pub const EVENTS: &[&str] = &["push", "pull_request", "workflow_run", "release"];
+
+/// `signature` is header value "sha256=<hex digest>"
+pub fn verify_signature(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let Some(digest) = signature.strip_prefix("sha256=") else { return false };
+    let Ok(digest) = hex::decode(digest) else { return false };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+
+    hmac::verify(&key, body, &digest).is_ok()
+}
+
+/// message for event, `None` if event (or its action) is not worth a message
+pub fn render(event: &str, payload: &Value) -> Option<String> {
+    match event {
+        "push" => Some(render_push(payload)),
+        "pull_request" => render_pull_request(payload),
+        "workflow_run" => render_workflow_run(payload),
+        "release" => render_release(payload),
+        _ => None,
+    }
+}
+
+fn repo(payload: &Value) -> String {
+    git::repo_link(field(payload, "/repository/full_name"), field(payload, "/repository/html_url"))
+}
+
+fn render_push(payload: &Value) -> String {
+    let commits: Vec<Commit> = payload["commits"].as_array().map(Vec::as_slice).unwrap_or_default().iter()
+        .map(|commit| Commit {
+            id: field(commit, "/id"),
+            message: field(commit, "/message"),
+            url: field(commit, "/url"),
+            author: field(commit, "/author/name"),
+        })
+        .collect();
+    let user = match field(payload, "/pusher/name") {
+        "" => field(payload, "/sender/login"),
+        name => name,
+    };
+
+    git::render_push(&Push {
+        user,
+        repo: field(payload, "/repository/full_name"),
+        repo_url: field(payload, "/repository/html_url"),
+        git_ref: field(payload, "/ref"),
+        created: payload["created"].as_bool().unwrap_or(false),
+        deleted: payload["deleted"].as_bool().unwrap_or(false),
+        forced: payload["forced"].as_bool().unwrap_or(false),
+        total_commits: commits.len(),
+        commits,
+        compare_url: field(payload, "/compare"),
+    })
+}
+
+fn render_pull_request(payload: &Value) -> Option<String> {
+    let merged = payload["pull_request"]["merged"].as_bool().unwrap_or(false);
+    let (icon, verb) = match field(payload, "/action") {
+        "opened" => ("🔀", "opened"),
+        "reopened" => ("🔀", "reopened"),
+        "ready_for_review" => ("🔀", "marked ready for review"),
+        "closed" if merged => ("🟣", "merged"),
+        "closed" => ("🚫", "closed"),
+        _ => return None,
+    };
+    let title = format!("#{} {}", payload["pull_request"]["number"], field(payload, "/pull_request/title"));
+
+    Some(format!(
+        "{icon} {} {verb} pull request {} in {}\n<code>{}</code> → <code>{}</code>",
+        super::escape_html(field(payload, "/sender/login")),
+        git::link(&title, field(payload, "/pull_request/html_url")),
+        repo(payload),
+        super::escape_html(field(payload, "/pull_request/head/ref")),
+        super::escape_html(field(payload, "/pull_request/base/ref")),
+    ))
+}
+
+fn render_workflow_run(payload: &Value) -> Option<String> {
+    if field(payload, "/action") != "completed" {
+        return None;
+    }
+    let conclusion = field(payload, "/workflow_run/conclusion");
+    let name = format!("{} #{}", field(payload, "/workflow_run/name"), payload["workflow_run"]["run_number"]);
+
+    Some(format!(
+        "{} Workflow {} {} in {}:<code>{}</code>",
+        git::result_icon(conclusion),
+        git::link(&name, field(payload, "/workflow_run/html_url")),
+        super::escape_html(conclusion),
+        repo(payload),
+        super::escape_html(field(payload, "/workflow_run/head_branch")),
+    ))
+}
+
+fn render_release(payload: &Value) -> Option<String> {
+    if field(payload, "/action") != "published" {
+        return None;
+    }
+    let name = match field(payload, "/release/name") {
+        "" => field(payload, "/release/tag_name"),
+        name => name,
+    };
+
+    Some(format!(
+        "🚀 {} published release {} in {}",
+        super::escape_html(field(payload, "/sender/login")),
+        git::link(name, field(payload, "/release/html_url")),
+        repo(payload),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH: &str = include_str!("fixtures/github_push.json");
+
+    #[test]
+    fn test_verify_signature() {
+        // example from GitHub documentation
+        assert!(verify_signature(b"It's a Secret to Everybody", b"Hello, World!",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"));
+        // recorded delivery of fixture
+        let signature = "sha256=53a6c0c9c0e185beba808ba295c5d50aeb44d6a064a17772d2915b95bd6c697b";
+        assert!(verify_signature(b"fixture-secret", PUSH.as_bytes(), signature));
+        assert!(!verify_signature(b"other-secret", PUSH.as_bytes(), signature));
+        assert!(!verify_signature(b"fixture-secret", &PUSH.as_bytes()[1..], signature));
+        assert!(!verify_signature(b"fixture-secret", PUSH.as_bytes(), &signature[7..]));
+        assert!(!verify_signature(b"fixture-secret", PUSH.as_bytes(), "sha256=zz"));
+    }
+
+    fn render_fixture(event: &str, fixture: &str) -> Option<String> {
+        render(event, &serde_json::from_str(fixture).unwrap())
+    }
+
+    #[test]
+    fn test_render_push() {
+        assert_eq!(render_fixture("push", PUSH).unwrap(),
+            "📦 octocat pushed 2 commits to <a href=\"https://github.com/octocat/notify-me-bot\">\
+            <b>octocat/notify-me-bot</b></a>:<code>main</code>\n\
+            • <a href=\"https://github.com/octocat/notify-me-bot/commit/b3fdc1a9be4a8d1a2e5c2f80a1b6b0f1f2e3d4c5\">\
+            <code>b3fdc1a</code></a> Fix retry of queued messages — Mona Lisa\n\
+            • <a href=\"https://github.com/octocat/notify-me-bot/commit/0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c\">\
+            <code>0d1a26e</code></a> Update README &lt;badges&gt; — octocat\n\
+            <a href=\"https://github.com/octocat/notify-me-bot/compare/6113728f27ae...0d1a26e67d8f\">Compare changes</a>");
+    }
+
+    #[test]
+    fn test_render_pull_request_and_workflow() {
+        let pull_request = include_str!("fixtures/github_pull_request.json");
+        assert_eq!(render_fixture("pull_request", pull_request).unwrap(),
+            "🟣 octocat merged pull request \
+            <a href=\"https://github.com/octocat/notify-me-bot/pull/42\">#42 Add Grafana receiver</a> in \
+            <a href=\"https://github.com/octocat/notify-me-bot\"><b>octocat/notify-me-bot</b></a>\n\
+            <code>grafana</code> → <code>main</code>");
+        let labeled = pull_request.replace("\"closed\"", "\"labeled\"");
+        assert_eq!(render_fixture("pull_request", &labeled), None);
+
+        let workflow_run = include_str!("fixtures/github_workflow_run.json");
+        assert_eq!(render_fixture("workflow_run", workflow_run).unwrap(),
+            "❌ Workflow <a href=\"https://github.com/octocat/notify-me-bot/actions/runs/5016383125\">CI #118</a> \
+            failure in <a href=\"https://github.com/octocat/notify-me-bot\"><b>octocat/notify-me-bot</b></a>:\
+            <code>main</code>");
+        assert_eq!(render_fixture("workflow_run", &workflow_run.replace("\"completed\",\n  \"workflow_run\"",
+                                                                         "\"requested\",\n  \"workflow_run\"")), None);
+        assert_eq!(render_fixture("star", "{}"), None);
+    }
+}
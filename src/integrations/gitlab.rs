@@ -0,0 +1,162 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! GitLab webhooks (https://docs.gitlab.com/ee/user/project/integrations/webhook_events.html).
+//!
+//! GitLab does not sign deliveries, it sends configured secret in X-Gitlab-Token header.
+//! Event is taken from `object_kind` of payload.
+
+use serde_json::Value;
+
+use super::{escape_html, field};
+use super::git::{self, Commit, Push};
+
+pub const TOKEN_HEADER: &str = "X-Gitlab-Token";
+/// events (object_kind) rendered to messages, others are ignored
+pub const EVENTS: &[&str] = &["push", "tag_push", "merge_request", "pipeline", "release"];
+/// "after" of push deleting branch or tag
+const ZERO_SHA: &str = "0000000000000000000000000000000000000000";
+
+pub fn verify_token(secret: &str, token: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(secret.as_bytes(), token.as_bytes()).is_ok()
+}
+
+pub fn event(payload: &Value) -> &str {
+    field(payload, "/object_kind")
+}
+
+/// message for event, `None` if event (or its action) is not worth a message
+pub fn render(payload: &Value) -> Option<String> {
+    match event(payload) {
+        "push" | "tag_push" => Some(render_push(payload)),
+        "merge_request" => render_merge_request(payload),
+        "pipeline" => render_pipeline(payload),
+        "release" => render_release(payload),
+        _ => None,
+    }
+}
+
+fn project(payload: &Value) -> String {
+    git::repo_link(field(payload, "/project/path_with_namespace"), field(payload, "/project/web_url"))
+}
+
+fn render_push(payload: &Value) -> String {
+    let commits: Vec<Commit> = payload["commits"].as_array().map(Vec::as_slice).unwrap_or_default().iter()
+        .map(|commit| Commit {
+            id: field(commit, "/id"),
+            message: field(commit, "/message"),
+            url: field(commit, "/url"),
+            author: field(commit, "/author/name"),
+        })
+        .collect();
+    let total_commits = payload["total_commits_count"].as_u64().map(|count| count as usize)
+        .unwrap_or(commits.len());
+
+    git::render_push(&Push {
+        user: field(payload, "/user_name"),
+        repo: field(payload, "/project/path_with_namespace"),
+        repo_url: field(payload, "/project/web_url"),
+        git_ref: field(payload, "/ref"),
+        created: field(payload, "/before") == ZERO_SHA,
+        deleted: field(payload, "/after") == ZERO_SHA,
+        forced: false,
+        commits,
+        total_commits,
+        compare_url: "",
+    })
+}
+
+fn render_merge_request(payload: &Value) -> Option<String> {
+    let (icon, verb) = match field(payload, "/object_attributes/action") {
+        "open" => ("🔀", "opened"),
+        "reopen" => ("🔀", "reopened"),
+        "merge" => ("🟣", "merged"),
+        "close" => ("🚫", "closed"),
+        _ => return None,
+    };
+    let title = format!("!{} {}", payload["object_attributes"]["iid"], field(payload, "/object_attributes/title"));
+
+    Some(format!(
+        "{icon} {} {verb} merge request {} in {}\n<code>{}</code> → <code>{}</code>",
+        escape_html(field(payload, "/user/name")),
+        git::link(&title, field(payload, "/object_attributes/url")),
+        project(payload),
+        escape_html(field(payload, "/object_attributes/source_branch")),
+        escape_html(field(payload, "/object_attributes/target_branch")),
+    ))
+}
+
+fn render_pipeline(payload: &Value) -> Option<String> {
+    let status = field(payload, "/object_attributes/status");
+    if !matches!(status, "success" | "failed" | "canceled") {
+        return None;
+    }
+    let id = &payload["object_attributes"]["id"];
+    let url = match field(payload, "/object_attributes/url") {
+        "" => format!("{}/-/pipelines/{id}", field(payload, "/project/web_url")),
+        url => url.to_string(),
+    };
+
+    Some(format!(
+        "{} Pipeline {} {} in {}:<code>{}</code>",
+        git::result_icon(status),
+        git::link(&format!("#{id}"), &url),
+        escape_html(status),
+        project(payload),
+        escape_html(field(payload, "/object_attributes/ref")),
+    ))
+}
+
+fn render_release(payload: &Value) -> Option<String> {
+    if field(payload, "/action") != "create" {
+        return None;
+    }
+    let name = match field(payload, "/name") {
+        "" => field(payload, "/tag"),
+        name => name,
+    };
+
+    Some(format!("🚀 Release {} created in {}", git::link(name, field(payload, "/url")), project(payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_fixture(fixture: &str) -> Option<String> {
+        render(&serde_json::from_str(fixture).unwrap())
+    }
+
+    #[test]
+    fn test_verify_token() {
+        assert!(verify_token("secret", "secret"));
+        assert!(!verify_token("secret", "secre"));
+        assert!(!verify_token("secret", ""));
+    }
+
+    #[test]
+    fn test_render_fixtures() {
+        assert_eq!(render_fixture(include_str!("fixtures/gitlab_push.json")).unwrap(),
+            "📦 John Smith pushed 4 commits to <a href=\"http://example.com/mike/diaspora\">\
+            <b>mike/diaspora</b></a>:<code>master</code>\n\
+            • <a href=\"http://example.com/mike/diaspora/commit/b6568db1bc1dcd7f8b4d5a946b0b91f9dacd7327\">\
+            <code>b6568db</code></a> Update Catalan translation to e38cb41. — Jordi Mallach\n\
+            • <a href=\"http://example.com/mike/diaspora/commit/da1560886d4f094c3e6c9ef40349f7d38b5d27d7\">\
+            <code>da15608</code></a> fixed readme — GitLab dev user\n\
+            …and 2 more");
+
+        assert_eq!(render_fixture(include_str!("fixtures/gitlab_merge_request.json")).unwrap(),
+            "🔀 Administrator opened merge request \
+            <a href=\"http://example.com/diaspora/merge_requests/1\">!1 MS-Viewport</a> in \
+            <a href=\"http://example.com/gitlabhq/gitlab-test\"><b>gitlabhq/gitlab-test</b></a>\n\
+            <code>ms-viewport</code> → <code>master</code>");
+
+        let pipeline = include_str!("fixtures/gitlab_pipeline.json");
+        assert_eq!(render_fixture(pipeline).unwrap(),
+            "✅ Pipeline <a href=\"http://example.com/gitlab-org/gitlab-test/-/pipelines/31\">#31</a> success in \
+            <a href=\"http://example.com/gitlab-org/gitlab-test\"><b>gitlab-org/gitlab-test</b></a>:<code>master</code>");
+        assert_eq!(render_fixture(&pipeline.replace("\"success\"", "\"running\"")), None);
+    }
+}
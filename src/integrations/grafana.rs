@@ -0,0 +1,180 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Grafana alerting webhook contact point payload
+//! (https://grafana.com/docs/grafana/latest/alerting/configure-notifications/manage-contact-points/integrations/webhook-notifier/).
+//!
+//! Unified alerting payload is Alertmanager one with title, state and message added.
+//! Fields of legacy alerting (imageUrl, ruleUrl) are accepted too.
+
+use std::fmt::Write;
+
+use serde::Deserialize;
+
+use super::{escape_html, is_url};
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    /// "alerting", "ok", "no_data", "pending"...
+    #[serde(default)]
+    pub state: String,
+    /// "firing" or "resolved", used if state is not set
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub message: String,
+    /// legacy alerting
+    #[serde(default)]
+    pub image_url: String,
+    /// legacy alerting
+    #[serde(default)]
+    pub rule_url: String,
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Alert {
+    #[serde(rename = "dashboardURL", default)]
+    pub dashboard_url: String,
+    #[serde(rename = "panelURL", default)]
+    pub panel_url: String,
+    #[serde(rename = "imageURL", default)]
+    pub image_url: String,
+}
+
+fn state_icon(state: &str) -> &'static str {
+    match state {
+        "alerting" | "firing" => "🔥",
+        "ok" | "resolved" => "✅",
+        "no_data" => "❔",
+        "pending" => "⏳",
+        "paused" => "⏸",
+        _ => "ℹ️",
+    }
+}
+
+/// url of screenshot of panel, if grafana image renderer made it
+pub fn image_url(notification: &Notification) -> Option<&str> {
+    std::iter::once(notification.image_url.as_str())
+        .chain(notification.alerts.iter().map(|alert| alert.image_url.as_str()))
+        .find(|url| is_url(url))
+}
+
+/// message text (telegram HTML). Image is not embedded, it is shown as link preview
+/// (invisible link in front of text, telegram previews first link)
+pub fn render(notification: &Notification) -> String {
+    let state = if notification.state.is_empty() { &notification.status } else { &notification.state };
+
+    let mut out = String::new();
+    if let Some(image_url) = image_url(notification) {
+        let _ = write!(out, "<a href=\"{}\">\u{200b}</a>", escape_html(image_url));
+    }
+    let title = if notification.title.is_empty() { state.to_uppercase() } else { notification.title.clone() };
+    let _ = writeln!(out, "{} <b>{}</b>", state_icon(state), escape_html(&title));
+    let message = notification.message.trim();
+    if !message.is_empty() {
+        let _ = writeln!(out, "\n{}", escape_html(message));
+    }
+
+    // alerts of one panel have same links
+    let mut links: Vec<(&str, &str)> = Vec::new();
+    for alert in &notification.alerts {
+        for link in [("Dashboard", alert.dashboard_url.as_str()), ("Panel", alert.panel_url.as_str())] {
+            if is_url(link.1) && !links.contains(&link) {
+                links.push(link);
+            }
+        }
+    }
+    if is_url(&notification.rule_url) {
+        links.push(("Rule", &notification.rule_url));
+    }
+    if !links.is_empty() {
+        let links: Vec<String> = links.iter()
+            .map(|(name, url)| format!("<a href=\"{}\">{name}</a>", escape_html(url)))
+            .collect();
+        let _ = writeln!(out, "\n{}", links.join(" | "));
+    }
+
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_unified_alerting() {
+        let notification: Notification = serde_json::from_value(serde_json::json!({
+            "receiver": "telegram",
+            "status": "firing",
+            "orgId": 1,
+            "alerts": [
+                {
+                    "status": "firing",
+                    "labels": {"alertname": "High latency"},
+                    "annotations": {},
+                    "startsAt": "2023-05-01T11:00:00Z",
+                    "dashboardURL": "https://grafana/d/abc?orgId=1",
+                    "panelURL": "https://grafana/d/abc?orgId=1&viewPanel=2",
+                    "imageURL": "https://grafana/render/1.png",
+                    "valueString": "[ var='B' value=1.5 ]"
+                },
+                {
+                    "status": "firing",
+                    "dashboardURL": "https://grafana/d/abc?orgId=1",
+                    "panelURL": "https://grafana/d/abc?orgId=1&viewPanel=2"
+                }
+            ],
+            "title": "[FIRING:2] High latency <api>",
+            "state": "alerting",
+            "message": "**Firing**\nValue: B=1.5\n"
+        })).unwrap();
+
+        assert_eq!(render(&notification), "<a href=\"https://grafana/render/1.png\">\u{200b}</a>\
+            🔥 <b>[FIRING:2] High latency &lt;api&gt;</b>\n\
+            \n\
+            **Firing**\n\
+            Value: B=1.5\n\
+            \n\
+            <a href=\"https://grafana/d/abc?orgId=1\">Dashboard</a> | \
+            <a href=\"https://grafana/d/abc?orgId=1&amp;viewPanel=2\">Panel</a>");
+    }
+
+    #[test]
+    fn test_render_legacy_alerting() {
+        let notification: Notification = serde_json::from_value(serde_json::json!({
+            "title": "[OK] Disk usage",
+            "ruleUrl": "https://grafana/d/xyz",
+            "state": "ok",
+            "message": ""
+        })).unwrap();
+
+        assert_eq!(image_url(&notification), None);
+        assert_eq!(render(&notification), "✅ <b>[OK] Disk usage</b>\n\
+            \n\
+            <a href=\"https://grafana/d/xyz\">Rule</a>");
+    }
+
+    #[test]
+    fn test_render_skips_non_http_urls() {
+        let notification: Notification = serde_json::from_value(serde_json::json!({
+            "title": "[Alerting] Disk usage",
+            "state": "alerting",
+            "imageURL": "/render/1.png",
+            "ruleUrl": "grafana/d/xyz",
+            "alerts": [
+                {"dashboardURL": "javascript:alert(1)", "panelURL": "", "imageURL": "https://grafana/render/2.png"}
+            ]
+        })).unwrap();
+
+        assert_eq!(image_url(&notification), Some("https://grafana/render/2.png"));
+        assert_eq!(render(&notification), "<a href=\"https://grafana/render/2.png\">\u{200b}</a>\
+            🔥 <b>[Alerting] Disk usage</b>");
+    }
+}
@@ -0,0 +1,135 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! ntfy style publishing (https://docs.ntfy.sh/publish/): body is message text, title,
+//! priority, tags and click url come in headers or query parameters.
+//!
+//! `curl -H "X-Title: backup" -H "X-Tags: floppy_disk" -d "backup done" https://.../send/<token>`
+
+use crate::telegram_bot::api_type::{ParseMode, SendMessageOptions};
+
+use super::escape_html;
+
+/// ntfy accepts several names for every option: X-Title, Title, ti, t ...
+pub const TITLE_NAMES: &[&str] = &["x-title", "title", "ti", "t"];
+pub const PRIORITY_NAMES: &[&str] = &["x-priority", "priority", "prio", "p"];
+pub const TAGS_NAMES: &[&str] = &["x-tags", "tags", "tag", "ta"];
+pub const CLICK_NAMES: &[&str] = &["x-click", "click"];
+
+/// tags shown as emoji in front of title, other tags are listed under message
+const TAG_EMOJIS: &[(&str, &str)] = &[
+    ("+1", "👍"), ("-1", "👎"), ("warning", "⚠️"), ("rotating_light", "🚨"), ("white_check_mark", "✅"),
+    ("heavy_check_mark", "✔️"), ("x", "❌"), ("no_entry", "⛔"), ("skull", "💀"), ("fire", "🔥"),
+    ("tada", "🎉"), ("rocket", "🚀"), ("bell", "🔔"), ("loudspeaker", "📢"), ("computer", "💻"),
+    ("floppy_disk", "💾"), ("hourglass", "⌛"), ("lock", "🔒"), ("bug", "🐛"), ("information_source", "ℹ️"),
+];
+
+#[derive(Debug, Default)]
+pub struct Publish<'a> {
+    pub message: &'a str,
+    pub title: Option<&'a str>,
+    pub priority: Option<&'a str>,
+    /// comma separated
+    pub tags: Option<&'a str>,
+    pub click: Option<&'a str>,
+}
+
+/// 1 (min) - 5 (max), `None` if value is not valid
+fn parse_priority(priority: &str) -> Option<u8> {
+    match priority.trim().to_lowercase().as_str() {
+        "1" | "min" => Some(1),
+        "2" | "low" => Some(2),
+        "3" | "default" => Some(3),
+        "4" | "high" => Some(4),
+        "5" | "max" | "urgent" => Some(5),
+        _ => None,
+    }
+}
+
+/// message text (telegram HTML) and options: low priority messages are sent silently
+pub fn render(publish: &Publish) -> Result<(String, SendMessageOptions), String> {
+    let priority = match publish.priority {
+        None => 3,
+        Some(priority) => parse_priority(priority)
+            .ok_or_else(|| format!("priority \"{priority}\" should be 1-5 or min, low, default, high, max"))?,
+    };
+    let mut emojis = Vec::new();
+    let mut other_tags = Vec::new();
+    for tag in publish.tags.unwrap_or_default().split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        match TAG_EMOJIS.iter().find(|(name, _)| *name == tag) {
+            Some((_, emoji)) => emojis.push(*emoji),
+            None => other_tags.push(escape_html(tag)),
+        }
+    }
+    match priority {
+        4 => emojis.insert(0, "❗"),
+        5 => emojis.insert(0, "🚨"),
+        _ => {},
+    }
+
+    let mut lines = Vec::new();
+    let title = publish.title.map(str::trim).filter(|title| !title.is_empty());
+    match (emojis.is_empty(), title) {
+        (_, Some(title)) => lines.push(format!("{} <b>{}</b>", emojis.join(""), escape_html(title)).trim_start().to_string()),
+        (false, None) => lines.push(emojis.join("")),
+        (true, None) => {},
+    }
+    let message = publish.message.trim_end();
+    if !message.is_empty() {
+        lines.push(escape_html(message));
+    }
+    if title.is_none() && message.is_empty() {
+        return Err("message is empty, send text in request body".to_string());
+    }
+    if !other_tags.is_empty() {
+        lines.push(format!("<i>tags: {}</i>", other_tags.join(", ")));
+    }
+    if let Some(click) = publish.click.filter(|click| click.starts_with("https://") || click.starts_with("http://")) {
+        lines.push(format!("<a href=\"{}\">Open</a>", escape_html(click)));
+    }
+    // emoji line and message on one line, as in ntfy
+    let text = if title.is_none() && !emojis.is_empty() && lines.len() > 1 {
+        let emojis = lines.remove(0);
+        format!("{emojis} {}", lines.join("\n"))
+    } else {
+        lines.join("\n")
+    };
+
+    let options = SendMessageOptions {
+        parse_mode: Some(ParseMode::HTML),
+        disable_notification: (priority <= 2).then_some(true),
+        ..Default::default()
+    };
+
+    Ok((text, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let (text, options) = render(&Publish {
+            message: "backup of <db> done\n",
+            title: Some("Backup"),
+            priority: Some("high"),
+            tags: Some("floppy_disk, nightly"),
+            click: Some("https://backup.example.com/?job=1&x=2"),
+        }).unwrap();
+        assert_eq!(text, "❗💾 <b>Backup</b>\nbackup of &lt;db&gt; done\n<i>tags: nightly</i>\n\
+            <a href=\"https://backup.example.com/?job=1&amp;x=2\">Open</a>");
+        assert_eq!(options.disable_notification, None);
+
+        let (text, options) = render(&Publish { message: "disk ok", priority: Some("1"), tags: Some("white_check_mark"),
+                                                ..Default::default() }).unwrap();
+        assert_eq!(text, "✅ disk ok");
+        assert_eq!(options.disable_notification, Some(true));
+
+        assert_eq!(render(&Publish { message: "plain", ..Default::default() }).unwrap().0, "plain");
+        assert!(render(&Publish { message: "x", priority: Some("7"), ..Default::default() }).is_err());
+        assert!(render(&Publish { message: " \n", ..Default::default() }).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Slack incoming webhook payload (https://api.slack.com/messaging/webhooks):
+//! text, Block Kit blocks and legacy attachments.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::chat_markup::{to_html, Dialect};
+use super::{escape_html, field, is_url};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Message {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub blocks: Vec<Value>,
+    #[serde(default)]
+    pub attachments: Vec<Value>,
+}
+
+/// slack text object: {"type": "mrkdwn" | "plain_text", "text": ...}
+fn text_object(value: &Value) -> String {
+    let text = field(value, "/text");
+    if field(value, "/type") == "plain_text" {
+        return escape_html(text);
+    }
+    to_html(text, Dialect::Slack)
+}
+
+/// link, or label only if url is not http(s)
+fn link(url: &str, label: &str) -> String {
+    if !is_url(url) {
+        return escape_html(label);
+    }
+    format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(label))
+}
+
+fn render_block(block: &Value) -> Option<String> {
+    match field(block, "/type") {
+        "header" => Some(format!("<b>{}</b>", escape_html(field(block, "/text/text")))),
+        "section" => {
+            let mut lines = Vec::new();
+            if block.get("text").is_some() {
+                lines.push(text_object(&block["text"]));
+            }
+            for item in block["fields"].as_array().into_iter().flatten() {
+                lines.push(text_object(item));
+            }
+            Some(lines.join("\n"))
+        },
+        "context" => {
+            let texts: Vec<String> = block["elements"].as_array().into_iter().flatten()
+                .filter(|element| element.get("text").is_some())
+                .map(text_object)
+                .collect();
+            Some(format!("<i>{}</i>", texts.join(" · ")))
+        },
+        "divider" => Some("――――――".to_string()),
+        "image" => Some(link(field(block, "/image_url"), match field(block, "/alt_text") {
+            "" => "image",
+            alt_text => alt_text,
+        })),
+        "actions" => {
+            let links: Vec<String> = block["elements"].as_array().into_iter().flatten()
+                .filter(|element| !field(element, "/url").is_empty())
+                .map(|element| link(field(element, "/url"), field(element, "/text/text")))
+                .collect();
+            (!links.is_empty()).then(|| links.join(" | "))
+        },
+        _ => None,
+    }
+}
+
+fn render_attachment(attachment: &Value) -> String {
+    let mut lines = Vec::new();
+    let mrkdwn = |name: &str| to_html(field(attachment, name), Dialect::Slack);
+    if !field(attachment, "/pretext").is_empty() {
+        lines.push(mrkdwn("/pretext"));
+    }
+    if !field(attachment, "/author_name").is_empty() {
+        lines.push(format!("<i>{}</i>", escape_html(field(attachment, "/author_name"))));
+    }
+    match (field(attachment, "/title"), field(attachment, "/title_link")) {
+        ("", _) => {},
+        (title, "") => lines.push(format!("<b>{}</b>", escape_html(title))),
+        (title, url) => lines.push(format!("<b>{}</b>", link(url, title))),
+    }
+    if !field(attachment, "/text").is_empty() {
+        lines.push(mrkdwn("/text"));
+    } else if lines.is_empty() && !field(attachment, "/fallback").is_empty() {
+        lines.push(escape_html(field(attachment, "/fallback")));
+    }
+    for item in attachment["fields"].as_array().into_iter().flatten() {
+        lines.push(format!("<b>{}</b>: {}", escape_html(field(item, "/title")),
+                           to_html(field(item, "/value"), Dialect::Slack)));
+    }
+    if !field(attachment, "/image_url").is_empty() {
+        lines.push(link(field(attachment, "/image_url"), "image"));
+    }
+    if !field(attachment, "/footer").is_empty() {
+        lines.push(format!("<i>{}</i>", escape_html(field(attachment, "/footer"))));
+    }
+
+    lines.join("\n")
+}
+
+/// message text (telegram HTML). Blocks replace text, as in Slack, text is used if no
+/// block is rendered
+pub fn render(message: &Message) -> String {
+    let blocks: Vec<String> = message.blocks.iter().filter_map(render_block).filter(|block| !block.is_empty()).collect();
+    let mut parts = if blocks.is_empty() {
+        vec![to_html(&message.text, Dialect::Slack)]
+    } else {
+        blocks
+    };
+    parts.extend(message.attachments.iter().map(render_attachment));
+    parts.retain(|part| !part.trim().is_empty());
+
+    parts.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "text": "fallback text",
+            "blocks": [
+                {"type": "header", "text": {"type": "plain_text", "text": "Deploy <prod>"}},
+                {"type": "section", "text": {"type": "mrkdwn", "text": "*api* deployed by <@U1>"},
+                 "fields": [{"type": "mrkdwn", "text": "*Version:*\n1.2.3"}]},
+                {"type": "divider"},
+                {"type": "context", "elements": [{"type": "mrkdwn", "text": "took 5m"}, {"type": "image", "image_url": "x"}]},
+                {"type": "actions", "elements": [{"type": "button", "text": {"type": "plain_text", "text": "Logs"},
+                                                  "url": "https://ci.example.com/1"},
+                                                 {"type": "button", "text": {"type": "plain_text", "text": "Open"},
+                                                  "url": "slack://open"}]},
+                {"type": "image", "image_url": "javascript:alert(1)", "alt_text": "graph"}
+            ],
+            "attachments": [{
+                "color": "#36a64f",
+                "title": "Changelog",
+                "title_link": "https://example.com/changelog",
+                "text": "_2 fixes_",
+                "fields": [{"title": "Env", "value": "production", "short": true}],
+                "footer": "ci bot"
+            }]
+        })).unwrap();
+
+        assert_eq!(render(&message), "<b>Deploy &lt;prod&gt;</b>\n\n\
+            <b>api</b> deployed by @U1\n<b>Version:</b>\n1.2.3\n\n\
+            ――――――\n\n\
+            <i>took 5m</i>\n\n\
+            <a href=\"https://ci.example.com/1\">Logs</a> | Open\n\n\
+            graph\n\n\
+            <b><a href=\"https://example.com/changelog\">Changelog</a></b>\n\
+            <i>2 fixes</i>\n\
+            <b>Env</b>: production\n\
+            <i>ci bot</i>");
+    }
+
+    #[test]
+    fn test_render_text_only() {
+        let message = Message { text: "disk *full* on <https://grafana.example.com|db-1>".to_string(), ..Default::default() };
+        assert_eq!(render(&message), "disk <b>full</b> on <a href=\"https://grafana.example.com\">db-1</a>");
+    }
+}
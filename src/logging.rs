@@ -0,0 +1,125 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Log output setup and helpers keeping secrets out of logs.
+//!
+//! Level is set by RUST_LOG (default `info`). Every http request runs in `http_request` span
+//! with request id (taken from X-Request-Id header or generated), every telegram update
+//! in `update` span, so all lines of one request can be found by span fields.
+
+use std::str::FromStr;
+use std::time::Instant;
+
+use axum::{
+    extract::MatchedPath
+    ,http::{HeaderValue, Request}
+    ,middleware::Next
+    ,response::Response
+};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::random;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format \"{s}\", expected text or json")),
+        }
+    }
+}
+
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    if let Err(err) = result {
+        eprintln!("failed init logging: {err}");
+    }
+}
+
+/// start of secret (enough to tell tokens apart), rest replaced with `***`
+pub fn redact(secret: &str) -> String {
+    // short secret is not shown at all, its prefix would be too big part of it
+    if secret.len() < 16 {
+        return "***".to_string();
+    }
+    let prefix: String = secret.chars().take(6).collect();
+    format!("{prefix}***")
+}
+
+/// request id from client if it is short printable string
+fn client_request_id(value: &HeaderValue) -> Option<String> {
+    let value = value.to_str().ok()?;
+    let valid = !value.is_empty() && value.len() <= 64
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    valid.then(|| value.to_string())
+}
+
+fn generate_request_id() -> String {
+    let mut id: [u8; 8] = [0; 8];
+    if random::gen_random(&mut id).is_err() {
+        return "-".to_string();
+    }
+    hex::encode(id)
+}
+
+/// middleware: span with request id around request, response gets X-Request-Id header
+pub async fn request_span<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(client_request_id)
+        .unwrap_or_else(generate_request_id);
+    // route pattern, not real path: path may contain token
+    let route = request.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let span = tracing::info_span!("http_request", request_id = %request_id, method = %request.method(), route = %route);
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        tracing::info!(status = response.status().as_u16(), elapsed_ms = started.elapsed().as_millis() as u64,
+                       "request finished");
+    });
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("123456:ABCDEFGHIJKLMNOPQRSTUVWXYZ"), "123456***");
+        assert_eq!(redact("short"), "***");
+        assert_eq!(redact(""), "***");
+    }
+
+    #[test]
+    fn test_client_request_id() {
+        assert_eq!(client_request_id(&HeaderValue::from_static("abc-123")), Some("abc-123".to_string()));
+        assert_eq!(client_request_id(&HeaderValue::from_static("a b")), None);
+        assert_eq!(client_request_id(&HeaderValue::from_static("")), None);
+    }
+}
@@ -4,66 +4,173 @@
  */
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::{self, header},
+    middleware,
     response::IntoResponse,
     Router,
     routing::{get, post},
 };
-use tower_http::cors::{Any, CorsLayer};
-use dotenv::dotenv;
-use std::env;
-use std::sync::{ Arc, Mutex };
-use std::time::SystemTime;
+use clap::Parser;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use std::path::PathBuf;
+use std::time::Duration;
 
+mod admin;
+mod config;
 mod error;
 mod http_handler;
+mod integrations;
+mod logging;
+mod metrics;
+mod outbox;
 mod random;
 pub mod telegram_bot;
 mod db;
-mod state;
+#[cfg(test)]
+mod integration_tests;
 
-use state::AppState;
+use config::{Config, ConfigLayer};
 use telegram_bot::TelegramBot;
 use crate::error::BotError;
 
+/// time to finish sends and update processing after http server stopped
+const SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// service sending messages from web to telegram chats
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    /// TOML config file [env: CONFIG_FILE] [default: ./notify-me-bot.toml if exists]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    layer: ConfigLayer,
+    #[command(subcommand)]
+    command: Option<admin::Command>,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), BotError> {
-    tracing_subscriber::fmt::init();
-
-    dotenv().expect("Failed open .env file");
-    let token   = env::var("TELEGRAM_BOT_TOKEN").expect("TELEGRAM_BOT_TOKEN not found in .env file");
-    let db_file = env::var("DB_FILE").expect("DB_FILE not found in .env file");
-    let listen_addr = env::var("LISTEN_ADDR").expect("LISTEN_ADDR not found in .env file");
-    let listen_port = env::var("LISTEN_PORT").expect("LISTEN_PORT not found in .env file");
-    let webhook_url = env::var("TELEGRAM_WEBHOOK").expect("TELEGRAM_WEBHOOK not found in .env file");
+    let cli = Cli::parse();
+    let config = match Config::load(cli.config, cli.layer) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        },
+    };
+    logging::init(config.log_format);
 
-    db::init(&db_file).await.expect("Failed init database");
-    TelegramBot::init(&token, &webhook_url).await?;
+    if let Err(err) = db::init(&config.db_file, config.db_pool_size).await {
+        // also database written by newer version of bot
+        eprintln!("failed init database {}: {err}", config.db_file);
+        std::process::exit(1);
+    }
+    match cli.command.unwrap_or(admin::Command::Serve) {
+        admin::Command::Serve => {},
+        command => {
+            if let Err(err) = admin::run(command, &config).await {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        },
+    }
+    TelegramBot::init(&config).await?;
+    TelegramBot::start().await?;
+    outbox::init()?;
 
     // telegram_bot.get_me().await;
 
     // telegram_bot.get_updates().await;
 
-    let shared_state = Arc::new( Mutex::new(AppState { prev_query_time: SystemTime::now() }) );
+    axum::Server::bind(&config.listen)
+        .serve(app(&config).into_make_service())
+        // waits for running requests (inline sends of /send-message, webhook calls)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    tracing::info!("http server stopped, finishing work");
+    let finish = async {
+        TelegramBot::stop().await;
+        outbox::shutdown().await;
+    };
+    if tokio::time::timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS), finish).await.is_err() {
+        // not delivered messages are in outbox table and will be sent after restart
+        tracing::warn!("shutdown took longer than {SHUTDOWN_TIMEOUT_SECS}s, stopping anyway");
+    }
+    db::close().await;
+    tracing::info!("stopped");
+
+    return Ok(());
+}
+
+/// SIGINT (Ctrl+C) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed listen for Ctrl+C: {err:?}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; },
+            Err(err) => {
+                tracing::error!("Failed listen for SIGTERM: {err:?}");
+                std::future::pending::<()>().await;
+            },
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutdown signal received");
+}
 
-    let app = Router::new()
+fn app(config: &Config) -> Router {
+    let allow_origin = if config.cors_origins.is_empty() {
+        AllowOrigin::from(Any)
+    } else {
+        AllowOrigin::list(config.cors_origins.clone())
+    };
+
+    Router::new()
         .route("/", get(root))
         .route("/scripts/notify-me.js", get(script_cjm))
-        .route("/webhook", post(http_handler::handle_webhook))
+        .route("/metrics", get(http_handler::handle_metrics))
+        .route("/healthz", get(http_handler::handle_healthz))
+        .route("/readyz", get(http_handler::handle_readyz))
+        .route(&config.webhook_path, post(http_handler::handle_webhook))
         .route("/send-message", post(http_handler::handle_message))
+        .route("/send/:token", post(integrations::handle_send).put(integrations::handle_send)
+            .layer(DefaultBodyLimit::max(integrations::MAX_MESSAGE_SIZE)))
+        .route("/send-file", post(http_handler::handle_file)
+            .layer(DefaultBodyLimit::max(config.max_upload_size)))
+        .route("/integrations/alertmanager/:token", post(integrations::handle_alertmanager)
+            .layer(DefaultBodyLimit::max(integrations::MAX_WEBHOOK_SIZE)))
+        .route("/integrations/grafana/:token", post(integrations::handle_grafana)
+            .layer(DefaultBodyLimit::max(integrations::MAX_WEBHOOK_SIZE)))
+        .route("/integrations/github/:token", post(integrations::handle_github)
+            .layer(DefaultBodyLimit::max(integrations::MAX_WEBHOOK_SIZE)))
+        .route("/integrations/gitlab/:token", post(integrations::handle_gitlab)
+            .layer(DefaultBodyLimit::max(integrations::MAX_WEBHOOK_SIZE)))
+        .route("/integrations/slack/:token", post(integrations::handle_slack)
+            .layer(DefaultBodyLimit::max(integrations::MAX_MESSAGE_SIZE)))
+        .route("/integrations/discord/:token", post(integrations::handle_discord)
+            .layer(DefaultBodyLimit::max(integrations::MAX_MESSAGE_SIZE)))
         .route_layer(CorsLayer::new()
-            .allow_methods([http::Method::GET, http::Method::POST, http::Method::OPTIONS])
+            .allow_methods([http::Method::GET, http::Method::POST, http::Method::PUT, http::Method::OPTIONS])
             .allow_headers([http::header::CONTENT_TYPE])
-            .allow_origin(Any))
-        .with_state(shared_state);
-
-    let bind_addr = format!("{listen_addr}:{listen_port}");
-    axum::Server::bind(&bind_addr.parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-
-    return Ok(());
+            .allow_origin(allow_origin))
+        .route_layer(middleware::from_fn(logging::request_span))
 }
 
 async fn root() -> &'static str {
@@ -73,8 +180,9 @@ pub async fn script_cjm() -> impl IntoResponse {
     let result = r#"
 "use strict";
 class NotifyMe {
-    static async sendMessage(url, token, message) {
-        const data = { token, message };
+    // options: { parse_mode, disable_notification, disable_web_page_preview, protect_content }
+    static async sendMessage(url, token, message, options = {}) {
+        const data = { ...options, token, message };
         const response = await fetch(url, {
             method: "POST",
             mode: "cors",
@@ -103,8 +211,8 @@ class NotifyMe {
             throw new Error("" + status);
         }
     }
-    static createSender(url, token) {
-        return (message) => NotifyMe.sendMessage(url, token, message);
+    static createSender(url, token, options = {}) {
+        return (message) => NotifyMe.sendMessage(url, token, message, options);
     }
 }
 "#;
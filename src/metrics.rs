@@ -0,0 +1,152 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Counters and histograms exported in Prometheus text format on /metrics.
+//!
+//! Values which can be read from other places (queue depth, sessions count, update mode)
+//! are not stored here, http handler appends them as gauges on every scrape.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+
+pub const HTTP_REQUESTS: &str = "notify_me_http_requests_total";
+pub const TELEGRAM_REQUESTS: &str = "notify_me_telegram_requests_total";
+pub const TELEGRAM_REQUEST_DURATION: &str = "notify_me_telegram_request_duration_seconds";
+pub const UPDATES: &str = "notify_me_updates_total";
+pub const UPDATE_DURATION: &str = "notify_me_update_processing_duration_seconds";
+
+/// name, type and help of every metric stored here
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (HTTP_REQUESTS, "counter", "API requests by endpoint and response code"),
+    (TELEGRAM_REQUESTS, "counter", "Bot API calls by method and result (ok or telegram error code)"),
+    (TELEGRAM_REQUEST_DURATION, "histogram", "Bot API call latency by method"),
+    (UPDATES, "counter", "Received telegram updates by result"),
+    (UPDATE_DURATION, "histogram", "Time of update processing"),
+];
+
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// count of observations in every bucket (not cumulative)
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// metric name and rendered labels (`{method="sendMessage"}`)
+type Key = (&'static str, String);
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<Key, u64>,
+    histograms: BTreeMap<Key, Histogram>,
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+pub fn inc_counter(name: &'static str, labels: &[(&str, &str)]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.counters.entry((name, render_labels(labels))).or_default() += 1;
+}
+
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let histogram = registry.histograms.entry((name, render_labels(labels))).or_default();
+    if let Some(bucket) = BUCKETS.iter().position(|bound| value <= *bound) {
+        histogram.buckets[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += value;
+}
+
+/// label set with one more label (histogram `le`)
+fn with_label(labels: &str, name: &str, value: &str) -> String {
+    match labels.strip_suffix('}') {
+        Some(labels) => format!("{labels},{name}=\"{value}\"}}"),
+        None => format!("{{{name}=\"{value}\"}}"),
+    }
+}
+
+/// gauge in text format
+pub fn render_gauge(out: &mut String, name: &str, help: &str, values: &[(&[(&str, &str)], f64)]) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge");
+    for (labels, value) in values {
+        let _ = writeln!(out, "{name}{} {value}", render_labels(labels));
+    }
+}
+
+/// all stored metrics in text format
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, metric_type, help) in DESCRIPTIONS {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {metric_type}");
+        for ((_, labels), value) in registry.counters.range((*name, String::new())..)
+            .take_while(|((key_name, _), _)| key_name == name)
+        {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+        for ((_, labels), histogram) in registry.histograms.range((*name, String::new())..)
+            .take_while(|((key_name, _), _)| key_name == name)
+        {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "{name}_bucket{} {cumulative}", with_label(labels, "le", &bound.to_string()));
+            }
+            let _ = writeln!(out, "{name}_bucket{} {}", with_label(labels, "le", "+Inf"), histogram.count);
+            let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+            let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        // metrics are global, use labels not used by other tests
+        inc_counter(HTTP_REQUESTS, &[("endpoint", "/test"), ("code", "200")]);
+        inc_counter(HTTP_REQUESTS, &[("endpoint", "/test"), ("code", "200")]);
+        observe(TELEGRAM_REQUEST_DURATION, &[("method", "testMethod")], 0.02);
+        observe(TELEGRAM_REQUEST_DURATION, &[("method", "testMethod")], 20.0);
+
+        let text = render();
+        assert!(text.contains("# TYPE notify_me_http_requests_total counter\n"));
+        assert!(text.contains("notify_me_http_requests_total{endpoint=\"/test\",code=\"200\"} 2\n"));
+        assert!(text.contains(
+            "notify_me_telegram_request_duration_seconds_bucket{method=\"testMethod\",le=\"0.01\"} 0\n"));
+        assert!(text.contains(
+            "notify_me_telegram_request_duration_seconds_bucket{method=\"testMethod\",le=\"0.025\"} 1\n"));
+        assert!(text.contains(
+            "notify_me_telegram_request_duration_seconds_bucket{method=\"testMethod\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("notify_me_telegram_request_duration_seconds_count{method=\"testMethod\"} 2\n"));
+    }
+
+    #[test]
+    fn test_label_escape() {
+        assert_eq!(render_labels(&[("a", "x\"y")]), "{a=\"x\\\"y\"}");
+        assert_eq!(render_labels(&[]), "");
+    }
+}
@@ -0,0 +1,300 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Persistent outbound message queue.
+//!
+//! Every message is stored in `outbox` table before it is sent to telegram. The caller
+//! tries to deliver it right away, on failure the message stays in the table and
+//! background worker retries it with exponential backoff (also after restart).
+//! Long messages are split and every part queued separately, worker keeps per chat order.
+
+use std::collections::HashSet;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+use crate::db;
+use crate::error::BotError;
+use crate::telegram_bot::{api_type, message_splitter, TelegramBot};
+
+/// delivery attempts before message marked as failed
+const MAX_ATTEMPTS: i64 = 12;
+/// inline sender owns new message for this time, worker will not touch it
+const INLINE_LEASE_SECS: i64 = 30;
+/// inline sender renews lease this often while part waits for rate limiter or telegram
+const LEASE_RENEW_SECS: u64 = 10;
+const FIRST_RETRY_DELAY_SECS: i64 = 5;
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+const FETCH_BATCH_SIZE: i64 = 16;
+/// worker rechecks table at least this often
+const MAX_IDLE_SECS: i64 = 60;
+
+static WAKEUP: OnceCell<Notify> = OnceCell::new();
+#[inline]
+fn wakeup() -> &'static Notify {
+    // admin commands send messages without worker
+    WAKEUP.get_or_init(Notify::new)
+}
+
+/// stop signal and task of background worker
+struct Worker {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+static WORKER: OnceCell<Mutex<Option<Worker>>> = OnceCell::new();
+
+/// message stored in outbox table as json
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutboxPayload {
+    pub text: String,
+    #[serde(flatten, default)]
+    pub options: api_type::SendMessageOptions,
+}
+
+#[derive(Debug)]
+pub enum Delivery {
+    /// all message parts delivered to telegram
+    Sent(Vec<api_type::ApiMessage>),
+    /// delivery failed, not sent parts stored in queue with given ids and will be retried
+    Queued { sent: Vec<api_type::ApiMessage>, queued: Vec<i64> },
+    /// telegram permanently rejected a part after `sent` parts were delivered,
+    /// the rest of message is dropped
+    PartlyRejected { sent: Vec<api_type::ApiMessage>, error: BotError },
+}
+
+pub fn init() -> Result<(), BotError>
+{
+    let (stop, stop_receiver) = watch::channel(false);
+    let task = tokio::spawn(async {
+        run_worker(stop_receiver).await;
+    });
+    if WORKER.set(Mutex::new(Some(Worker { stop, task }))).is_err() {
+        tracing::error!("outbox worker started twice");
+    }
+
+    Ok(())
+}
+
+/// stop worker after it sends messages which are due now. Messages waiting for retry
+/// stay in table and are sent after restart
+pub async fn shutdown()
+{
+    let Some(worker) = WORKER.get() else { return };
+    if let Some(worker) = worker.lock().await.take() {
+        let _ = worker.stop.send(true);
+        if let Err(err) = worker.task.await {
+            tracing::error!("outbox worker task failed: {err:?}");
+        }
+    }
+}
+
+/// split message to parts, store them in queue and try to deliver,
+/// returns `BotError::TelegramApi` if telegram rejected first part permanently
+pub async fn send_message(
+    chat_id: i64, text: &str, options: &api_type::SendMessageOptions
+) -> Result<Delivery, BotError>
+{
+    let payloads: Vec<OutboxPayload> = message_splitter::split_message(text, options.parse_mode)
+        .into_iter()
+        .map(|text| OutboxPayload { text, options: options.clone() })
+        .collect();
+    let payload_strings = payloads.iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<String>, _>>()?;
+    let lease_until = db::unix_time_current() + INLINE_LEASE_SECS;
+    let ids = db::enqueue_outbox(chat_id, &payload_strings, lease_until).await?;
+
+    if db::outbox_has_earlier(chat_id, ids[0]).await? {
+        // older messages for this chat still pending, keep order and let worker send it
+        release(&ids).await?;
+        return Ok(Delivery::Queued { sent: Vec::new(), queued: ids });
+    }
+
+    let mut sent = Vec::with_capacity(ids.len());
+    for (i, (id, payload)) in ids.iter().zip(&payloads).enumerate() {
+        match deliver_leased(chat_id, payload, &ids[i..]).await {
+            Ok(api_message) => {
+                db::delete_outbox(*id).await?;
+                sent.push(api_message);
+            },
+            Err(err) if err.is_permanent() => {
+                // caller gets error right away, nothing to retry
+                tracing::warn!("outbox message {id} for chat {chat_id} rejected by telegram: {err:?}");
+                for id in &ids[i..] {
+                    db::delete_outbox(*id).await?;
+                }
+                if sent.is_empty() {
+                    return Err(err);
+                }
+                return Ok(Delivery::PartlyRejected { sent, error: err });
+            },
+            Err(err) => {
+                tracing::warn!("outbox message {id} for chat {chat_id} delivery failed, queued: {err:?}");
+                schedule_retry(*id, 1, &err).await?;
+                release(&ids[i + 1..]).await?;
+                return Ok(Delivery::Queued { sent, queued: ids[i..].to_vec() });
+            },
+        }
+    }
+
+    Ok(Delivery::Sent(sent))
+}
+
+/// hand messages over to worker
+async fn release(ids: &[i64]) -> Result<(), BotError>
+{
+    db::set_outbox_lease(ids, db::unix_time_current()).await?;
+    wakeup().notify_one();
+
+    Ok(())
+}
+
+async fn deliver(chat_id: i64, payload: &OutboxPayload) -> Result<api_type::ApiMessage, BotError>
+{
+    match TelegramBot::send_message_with_options(chat_id, &payload.text, &payload.options).await {
+        Err(BotError::TelegramApi { migrate_to_chat_id: Some(migrate_to_chat_id), .. }) => {
+            tracing::info!("chat {chat_id} migrated to {migrate_to_chat_id}, updating sessions");
+            db::migrate_chat(chat_id, migrate_to_chat_id).await?;
+            return TelegramBot::send_message_with_options(migrate_to_chat_id, &payload.text, &payload.options).await;
+        },
+        result => result,
+    }
+}
+
+/// inline delivery keeps lease of not sent parts, so worker does not send them second time
+/// when rate limiter or slow telegram response delays it longer than lease
+async fn deliver_leased(
+    chat_id: i64, payload: &OutboxPayload, leased_ids: &[i64]
+) -> Result<api_type::ApiMessage, BotError>
+{
+    let delivery = deliver(chat_id, payload);
+    tokio::pin!(delivery);
+    loop {
+        tokio::select! {
+            result = &mut delivery => return result,
+            _ = sleep(Duration::from_secs(LEASE_RENEW_SECS)) => {
+                let lease_until = db::unix_time_current() + INLINE_LEASE_SECS;
+                if let Err(err) = db::set_outbox_lease(leased_ids, lease_until).await {
+                    tracing::warn!("failed renew lease of outbox messages {leased_ids:?}: {err:?}");
+                }
+            },
+        }
+    }
+}
+
+/// delay before next attempt, after `attempts` failed ones
+fn retry_delay(attempts: i64) -> i64 {
+    let shift = (attempts - 1).clamp(0, 20) as u32;
+    (FIRST_RETRY_DELAY_SECS << shift).min(MAX_RETRY_DELAY_SECS)
+}
+
+async fn schedule_retry(id: i64, attempts: i64, err: &BotError) -> Result<(), BotError>
+{
+    let error_str = err.to_string();
+    if err.is_permanent() {
+        tracing::error!("outbox message {id} rejected by telegram, giving up: {error_str}");
+        return db::fail_outbox(id, attempts, &error_str).await;
+    }
+    if attempts >= MAX_ATTEMPTS {
+        tracing::error!("outbox message {id} not delivered after {attempts} attempts, giving up: {error_str}");
+        return db::fail_outbox(id, attempts, &error_str).await;
+    }
+    let mut delay = retry_delay(attempts);
+    if let BotError::TelegramApi { retry_after: Some(retry_after), .. } = err {
+        delay = delay.max(*retry_after as i64);
+    }
+    let next_attempt_at = db::unix_time_current() + delay;
+
+    return db::reschedule_outbox(id, attempts, next_attempt_at, &error_str).await;
+}
+
+async fn run_worker(mut stop: watch::Receiver<bool>) {
+    tracing::info!("outbox worker started");
+    loop {
+        let result = process_due().await;
+        if *stop.borrow() {
+            break;
+        }
+        if let Err(err) = result {
+            tracing::error!("outbox worker got error {err:?}");
+            tokio::select! {
+                _ = stop.changed() => {},
+                _ = sleep(Duration::from_secs(FIRST_RETRY_DELAY_SECS as u64)) => {},
+            }
+            continue;
+        }
+        let delay = match db::next_outbox_attempt_time().await {
+            Ok(Some(next_time)) => (next_time - db::unix_time_current()).clamp(0, MAX_IDLE_SECS),
+            Ok(None) => MAX_IDLE_SECS,
+            Err(err) => {
+                tracing::error!("outbox worker failed get next attempt time {err:?}");
+                FIRST_RETRY_DELAY_SECS
+            },
+        };
+        if delay > 0 {
+            // on stop one more pass sends messages queued meanwhile
+            tokio::select! {
+                _ = stop.changed() => {},
+                _ = wakeup().notified() => {},
+                _ = sleep(Duration::from_secs(delay as u64)) => {},
+            }
+        }
+    }
+    tracing::info!("outbox worker stopped");
+}
+
+/// deliver all messages whose time has come
+async fn process_due() -> Result<(), BotError>
+{
+    loop {
+        let messages = db::fetch_due_outbox(db::unix_time_current(), FETCH_BATCH_SIZE).await?;
+        if messages.is_empty() {
+            return Ok(());
+        }
+        // chats with failed message in this batch, following messages should wait
+        let mut blocked_chats = HashSet::new();
+        for message in messages {
+            if blocked_chats.contains(&message.chat_id) {
+                continue;
+            }
+            let payload: OutboxPayload = match serde_json::from_str(&message.payload) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    tracing::error!("outbox message {} has broken payload: {err:?}", message.id);
+                    db::fail_outbox(message.id, message.attempts, &err.to_string()).await?;
+                    continue;
+                },
+            };
+            let attempts = message.attempts + 1;
+            match deliver(message.chat_id, &payload).await {
+                Ok(_) => {
+                    tracing::info!("outbox message {} delivered after {attempts} attempts", message.id);
+                    db::delete_outbox(message.id).await?;
+                },
+                Err(err) => {
+                    tracing::warn!("outbox message {} attempt {attempts} failed: {err:?}", message.id);
+                    blocked_chats.insert(message.chat_id);
+                    schedule_retry(message.id, attempts, &err).await?;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), FIRST_RETRY_DELAY_SECS);
+        assert_eq!(retry_delay(2), FIRST_RETRY_DELAY_SECS * 2);
+        assert_eq!(retry_delay(4), FIRST_RETRY_DELAY_SECS * 8);
+        assert_eq!(retry_delay(MAX_ATTEMPTS), MAX_RETRY_DELAY_SECS);
+        assert_eq!(retry_delay(1000), MAX_RETRY_DELAY_SECS);
+    }
+}
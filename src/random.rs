@@ -14,13 +14,12 @@ pub fn gen_random(buffer: &mut [u8]) -> Result<(),BotError>
     let sys_random = unsafe { SYS_RANDOM.get_unchecked() };
     sys_random.fill(buffer).map_err(|_err| {BotError::RingError()})?;
 
-    return Ok(());
+    Ok(())
 }
 
 pub fn init() -> Result<(), BotError>
 {
-    let sys_random = ring::rand::SystemRandom::new();
-    SYS_RANDOM.set(sys_random).unwrap();
+    SYS_RANDOM.get_or_init(ring::rand::SystemRandom::new);
 
-    return Ok(());
+    Ok(())
 }
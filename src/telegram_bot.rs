@@ -3,116 +3,372 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::sync::Arc;
-use base64::Engine;
-use base64::engine::general_purpose::NO_PAD;
+use std::future::Future;
 use hyper::{client::HttpConnector, body::to_bytes, client, Body};
 use hyper_rustls::{ConfigBuilderExt, HttpsConnector};
 
+use crate::config::Config;
 use crate::error::BotError;
 
 pub mod api_type;
+mod dispatcher;
+pub mod message_splitter;
+#[cfg(test)]
+pub mod mock_api;
+pub mod multipart;
+mod rate_limiter;
+pub mod update_mode;
 mod updates_handler;
+pub mod webhook_secret;
 use once_cell::sync::OnceCell;
-use tokio::sync::Mutex;
-use crate::random;
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+use crate::{db, metrics, random};
+use rate_limiter::RateLimiter;
+use multipart::{FileUpload, MultipartForm};
+use update_mode::{ModeState, Observation, UpdateMode};
+use webhook_secret::WebhookSecret;
+
+/// flood control waits not longer than this are handled inside send_message
+const MAX_INLINE_FLOOD_WAIT_SECS: u64 = 5;
+/// time Bot API gets to answer (getUpdates gets it on top of long polling timeout),
+/// stalled connection would block sender forever
+const REQUEST_TIMEOUT_SECS: u64 = 60;
+/// larger images are sent as documents
+const MAX_PHOTO_SIZE: usize = 10 * 1024 * 1024;
+/// Bot API upload limit
+pub const MAX_DOCUMENT_SIZE: usize = 50 * 1024 * 1024;
+
+pub const DEFAULT_API_URL: &str = "https://api.telegram.org";
+/// update processing or polling silent longer than this makes bot not ready
+const UPDATE_STALL_SECS: i64 = 120;
 
 static TG_BOT: OnceCell<TelegramBot> = OnceCell::new();
 #[inline]
 fn bot() -> &'static TelegramBot {
     unsafe { TG_BOT.get_unchecked() }
 }
-#[derive(PartialEq,Debug)]
-enum PollingMode {
-    Unknown,
-    Polling,
-    Webhook,
+/// running getUpdates loop
+#[derive(Debug)]
+struct Poller {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+#[derive(Debug)]
+struct ModeControl {
+    state: ModeState,
+    /// only one poller exists, it is started and stopped under ModeControl lock
+    poller: Option<Poller>,
+}
+/// update mode controller task
+#[derive(Debug)]
+struct Controller {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
 }
 #[derive(Debug)]
 pub struct TelegramBot {
     token: String,
+    api_url: String,
     https_client: HttpsClient,
     webhook_url: String,
-    webhook_token: String,
-    polling_mode: Arc<Mutex<PollingMode>>
+    webhook_secret: WebhookSecret,
+    update_mode: UpdateMode,
+    /// getUpdates long polling timeout
+    poll_timeout: u32,
+    mode_control: Mutex<ModeControl>,
+    controller: Mutex<Option<Controller>>,
+    /// wakes controller when poller stops by error
+    poller_failed: Notify,
+    rate_limiter: RateLimiter,
+    /// getMe result, set when bot started
+    bot_user: OnceCell<api_type::ApiUser>,
 }
 
 impl TelegramBot {
-    pub async fn init(token: &str, webhook_url: &str) -> Result<(),BotError> {
-        random::init();
+    /// `config.api_url` is Bot API server base url, `DEFAULT_API_URL` or self-hosted
+    /// telegram-bot-api server (plain http allowed).
+    /// If `config.webhook_secret` not set random one is generated and stored in db
+    pub async fn init(config: &Config) -> Result<(),BotError> {
+        random::init()?;
         {
-            let https_client: HttpsClient = create_https_client();
+            let api_url = config.api_url.trim_end_matches('/').to_string();
+            let https_client: HttpsClient = create_https_client(api_url.starts_with("http://"));
 
-            let mut webtoken_buf: [u8; 32] = [0; 32];
-            random::gen_random(&mut webtoken_buf[..])?;
-
-            let token_alphabet =
-                base64::alphabet::Alphabet::new("-_ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789")
-                    .unwrap();
-            let token_engine = base64::engine::GeneralPurpose::new(&token_alphabet, NO_PAD);
-            let webhook_token = token_engine.encode(&webtoken_buf);
+            let webhook_secret = webhook_secret::load(config.webhook_secret.as_deref()).await?;
 
             let bot = TelegramBot {
-                token: token.to_string()
+                token: config.bot_token.clone()
+                ,api_url
                 ,https_client
-                ,webhook_url: webhook_url.to_string()
-                ,webhook_token
-                ,polling_mode: Arc::new(Mutex::new(PollingMode::Unknown))};
+                ,webhook_url: config.webhook_url.clone()
+                ,webhook_secret
+                ,update_mode: config.update_mode
+                ,poll_timeout: config.poll_timeout
+                ,mode_control: Mutex::new(ModeControl { state: ModeState::Stopped, poller: None })
+                ,controller: Mutex::new(None)
+                ,poller_failed: Notify::new()
+                ,rate_limiter: RateLimiter::new()
+                ,bot_user: OnceCell::new()};
             TG_BOT.set(bot).unwrap();
         }
 
-        updates_handler::init();
+        Ok(())
+    }
+    /// start receiving updates (webhook or polling), without it bot can only send messages
+    pub async fn start() -> Result<(),BotError> {
+        updates_handler::init()?;
+        dispatcher::init();
+        let bot_user = bot().get_me().await?;
+        tracing::info!("bot started as @{}", bot_user.username.as_deref().unwrap_or_default());
+        let _ = bot().bot_user.set(bot_user);
         bot().set_my_commands().await?;
-        Self::set_mode_polling().await?;
+        let (stop, stop_receiver) = watch::channel(false);
+        let task = tokio::spawn( async {
+            bot().run_update_mode(stop_receiver).await;
+        });
+        *bot().controller.lock().await = Some(Controller { stop, task });
 
-        return Ok(());
+        Ok(())
+    }
+    /// stop receiving updates: poller confirms handled updates, webhook stays set
+    /// (telegram keeps updates until next start). Waits for processing of received updates
+    pub async fn stop() {
+        let bot = bot();
+        if let Some(controller) = bot.controller.lock().await.take() {
+            let _ = controller.stop.send(true);
+            if let Err(err) = controller.task.await {
+                tracing::error!("update mode controller task failed: {err:?}");
+            }
+        }
+        let mut control = bot.mode_control.lock().await;
+        if let Some(poller) = control.poller.take() {
+            let _ = poller.stop.send(true);
+            if let Err(err) = poller.task.await {
+                tracing::error!("Telegram poller task failed: {err:?}");
+            }
+        }
+        control.state = ModeState::Stopped;
+        drop(control);
+        dispatcher::flush().await;
     }
     pub async fn send_message(
         chat_id: i64, text: &str
     ) -> Result<api_type::ApiMessage, BotError>
     {
-        return bot().send_message_imp( chat_id, text ).await;
+        return bot().send_message_imp( chat_id, text, &api_type::SendMessageOptions::default() ).await;
     }
-    pub async fn set_mode_webhook() -> Result<(),BotError> {
-        return bot().set_mode_webhook_impl().await;
+    pub async fn send_message_with_options(
+        chat_id: i64, text: &str, options: &api_type::SendMessageOptions
+    ) -> Result<api_type::ApiMessage, BotError>
+    {
+        return bot().send_message_imp( chat_id, text, options ).await;
+    }
+    /// send file as photo (small jpeg, png and webp images) or document
+    pub async fn send_file(
+        chat_id: i64, file: &FileUpload, caption: Option<&str>, options: &api_type::SendMessageOptions
+    ) -> Result<api_type::ApiMessage, BotError>
+    {
+        return bot().send_file_impl( chat_id, file, caption, options ).await;
+    }
+    /// controller of update receiving mode, runs until `stop` is set
+    async fn run_update_mode(&self, mut stop: watch::Receiver<bool>) {
+        tracing::info!("update mode {:?}", self.update_mode);
+        let mut observation = Observation::Startup;
+        // failed switches in a row and failed webhook tries since webhook worked last time
+        let mut switch_failures: u32 = 0;
+        let mut webhook_failures: u32 = 0;
+        let mut webhook_checked_at = db::unix_time_current();
+        loop {
+            let current = self.mode_control.lock().await.state;
+            let target = update_mode::next_state(self.update_mode, current, observation);
+            let mut failed_target = None;
+            if target != current {
+                tracing::info!("switching update mode {current:?} -> {target:?} ({observation:?})");
+                match self.switch_mode(target).await {
+                    Ok(()) => {
+                        switch_failures = 0;
+                        if target == ModeState::Webhook {
+                            webhook_failures = 0;
+                            webhook_checked_at = db::unix_time_current();
+                        }
+                    },
+                    Err(err) => {
+                        tracing::error!("Failed switch update mode to {target:?}: {err:?}");
+                        switch_failures += 1;
+                        if target == ModeState::Webhook {
+                            webhook_failures += 1;
+                        }
+                        failed_target = Some(target);
+                    },
+                }
+            }
+
+            let state = self.mode_control.lock().await.state;
+            let failures = if state == ModeState::Stopped { switch_failures } else { webhook_failures };
+            // stop is checked only between switches, so switch is never interrupted
+            tokio::select! {
+                _ = stop.changed() => break,
+                _ = self.poller_failed.notified() => {},
+                _ = sleep(update_mode::check_delay(self.update_mode, state, failures)) => {},
+            }
+            if self.reset_failed_poller().await {
+                // restart it after retry delay, poller failing right away should not spin
+                switch_failures += 1;
+                tokio::select! {
+                    _ = stop.changed() => break,
+                    _ = sleep(update_mode::check_delay(self.update_mode, ModeState::Stopped, switch_failures)) => {},
+                }
+                observation = Observation::PollerFailed;
+                continue;
+            }
+
+            observation = match (failed_target, state) {
+                (Some(ModeState::Webhook), _) => Observation::WebhookSetFailed,
+                (Some(_), _) => Observation::PollingStartFailed,
+                (None, ModeState::Webhook) => {
+                    let observation = self.check_webhook(webhook_checked_at).await;
+                    webhook_checked_at = db::unix_time_current();
+                    observation
+                },
+                (None, _) => Observation::RetryTimer,
+            };
+        }
     }
-    async fn set_mode_webhook_impl(&self) -> Result<(),BotError> {
-        let mut lock_guard = self.polling_mode.lock().await;
-        if *lock_guard == PollingMode::Webhook {
-            return Ok(());
+    /// switch to webhook or polling. Updates are not lost or duplicated by switch:
+    /// poller confirms handled updates before setWebhook, and deleteWebhook keeps
+    /// pending updates for getUpdates
+    async fn switch_mode(&self, target: ModeState) -> Result<(),BotError> {
+        let mut control = self.mode_control.lock().await;
+        match target {
+            ModeState::Webhook => {
+                if let Some(poller) = control.poller.take() {
+                    control.state = ModeState::Stopped;
+                    let _ = poller.stop.send(true);
+                    if let Err(err) = poller.task.await {
+                        tracing::error!("Telegram poller task failed: {err:?}");
+                    }
+                }
+                self.set_webhook().await?;
+            },
+            ModeState::Polling => {
+                self.query_with_params("deleteWebhook", r#"{"drop_pending_updates":false}"#).await?;
+                let poller_running = control.poller.as_ref()
+                    .map(|poller| !poller.task.is_finished())
+                    .unwrap_or(false);
+                if !poller_running {
+                    let (stop, stop_receiver) = watch::channel(false);
+                    let task = tokio::spawn( async move {
+                        if let Err(err) = updates_handler::poll_updates(stop_receiver).await {
+                            tracing::error!("Telegram poller stopped with error {err:?}");
+                            bot().poller_failed.notify_one();
+                        }
+                    });
+                    control.poller = Some(Poller { stop, task });
+                }
+            },
+            ModeState::Stopped => {},
         }
+        control.state = target;
 
-        self.set_webhook().await?;
-        *lock_guard = PollingMode::Webhook;
+        Ok(())
+    }
+    /// true if state is polling but poller task ended, state is reset to stopped
+    /// so controller starts poller again
+    async fn reset_failed_poller(&self) -> bool {
+        let mut control = self.mode_control.lock().await;
+        let poller_running = control.poller.as_ref()
+            .map(|poller| !poller.task.is_finished())
+            .unwrap_or(false);
+        if control.state != ModeState::Polling || poller_running {
+            return false;
+        }
+        tracing::warn!("Telegram poller is not running, restarting it");
+        control.poller = None;
+        control.state = ModeState::Stopped;
 
-        return Ok(());
+        true
     }
-    pub async fn set_mode_polling() -> Result<(),BotError> {
-        return bot().set_mode_polling_impl().await;
+    /// in auto mode asks telegram if it can deliver updates to webhook
+    async fn check_webhook(&self, since: i64) -> Observation {
+        if self.update_mode != UpdateMode::Auto {
+            return Observation::WebhookHealthy;
+        }
+        let info: api_type::ApiWebhookInfo = match self.query("getWebhookInfo").await
+            .and_then(|json_value| serde_json::from_value(json_value).map_err(BotError::from))
+        {
+            Ok(info) => info,
+            Err(err) => {
+                tracing::warn!("getWebhookInfo failed: {err:?}");
+                return Observation::WebhookHealthy;
+            },
+        };
+        if info.url.is_empty() {
+            tracing::warn!("webhook was removed outside of this bot");
+            return Observation::WebhookFailing;
+        }
+        match info.last_error_date {
+            Some(error_date) if error_date >= since && info.pending_update_count > 0 => {
+                tracing::warn!("telegram can not deliver updates to webhook: {}"
+                    , info.last_error_message.unwrap_or_default());
+                Observation::WebhookFailing
+            },
+            _ => Observation::WebhookHealthy,
+        }
     }
-    async fn set_mode_polling_impl(&self) -> Result<(),BotError> {
-        let mut lock_guard = self.polling_mode.lock().await;
-        if *lock_guard == PollingMode::Polling {
-            return Ok(());
+    /// secret token sent by telegram in X-Telegram-Bot-Api-Secret-Token header
+    pub fn is_webhook_token_valid(webhook_token: &str) -> bool {
+        if !bot().webhook_secret.is_valid(webhook_token, db::unix_time_current()) {
+            tracing::warn!("webhook request with incorrect secret token rejected");
+            return false;
         }
-
-        self.query("deleteWebhook").await?;
-        tokio::spawn( async {
-            updates_handler::poll_updates().await.unwrap();
-        });
-        *lock_guard = PollingMode::Polling;
-
-        return Ok(());
+        true
+    }
+    /// queue update received by webhook, it is processed in background.
+    /// `BotError::UpdateQueueFull` if queue of its chat worker is full
+    pub fn enqueue_update(api_update: api_type::ApiUpdate) -> Result<(), BotError> {
+        dispatcher::dispatch( api_update )
+    }
+    /// how updates are received right now, `None` while mode is being switched
+    /// (lock is held during Bot API calls)
+    pub fn mode_state() -> Option<ModeState> {
+        bot().mode_control.try_lock().ok().map(|control| control.state)
+    }
+    /// received updates waiting for processing
+    pub fn update_queue_depth() -> usize {
+        dispatcher::queue_depth()
+    }
+    /// true after successful start (Bot API answered getMe)
+    pub fn is_started() -> bool {
+        bot().bot_user.get().is_some()
     }
-    pub async fn handle_webhook_update(
-        webhook_token: &str, api_update: api_type::ApiUpdate
-    ) -> Result<(), BotError> {
-        let current_token: &str = &bot().webhook_token;
-        if webhook_token != current_token {
-            println!("handle_webhook_update() got incorrect token\n{webhook_token} expected\n{current_token}");
-            return Ok(());
+    /// reason updates are not received or not processed, `None` if pipeline works
+    pub async fn update_pipeline_problem() -> Option<String> {
+        let now = db::unix_time_current();
+        if let Some(busy_secs) = dispatcher::longest_busy_secs(now) {
+            if busy_secs > UPDATE_STALL_SECS {
+                return Some(format!("update processing takes {busy_secs}s"));
+            }
         }
-        return updates_handler::handle_update( api_update ).await;
+        let bot = bot();
+        match &*bot.controller.lock().await {
+            Some(controller) if !controller.task.is_finished() => {},
+            _ => return Some("update mode controller is not running".to_string()),
+        }
+        // lock is held while mode is being switched, nothing to check then
+        let Ok(control) = bot.mode_control.try_lock() else { return None };
+        if control.state == ModeState::Polling {
+            if control.poller.as_ref().map(|poller| poller.task.is_finished()).unwrap_or(true) {
+                return Some("poller is not running".to_string());
+            }
+            let silence_secs = now - updates_handler::last_poll_time();
+            if silence_secs > bot.poll_timeout as i64 + UPDATE_STALL_SECS {
+                return Some(format!("no getUpdates response for {silence_secs}s"));
+            }
+        }
+
+        None
     }
 
     async fn get_me(&self) -> Result<api_type::ApiUser, BotError> {
@@ -132,33 +388,40 @@ impl TelegramBot {
         }
         let params_str = serde_json::to_string(&params)?;
 
-        let json_value = self.query_with_params("setMyCommands", &params_str).await?;
+        let _json_value = self.query_with_params("setMyCommands", &params_str).await?;
         // println!("setMyCommands returned {json_value:?}");
 
-        return Ok(());
+        Ok(())
     }
 
     async fn set_webhook(&self) -> Result<(), BotError>
     {
-        let mut params = api_type::SetWebhookParams::default();
-        params.url = &self.webhook_url;
-        params.secret_token = &self.webhook_token;
+        let params = api_type::SetWebhookParams {
+            url: &self.webhook_url
+            ,secret_token: &self.webhook_secret.current
+        };
         let params_str = serde_json::to_string(&params)?;
 
         let json_value = self.query_with_params("setWebhook", &params_str).await?;
-        println!("setWebhook returned {json_value:?}");
+        tracing::debug!("setWebhook returned {json_value:?}");
 
-        return Ok(());
+        Ok(())
     }
 
-    async fn get_updates(offset: i64) -> Result<Vec<api_type::ApiUpdate>, BotError> {
+    fn poll_timeout() -> u32 {
+        bot().poll_timeout
+    }
+    /// long polling for `timeout` seconds, getUpdates with `offset` confirms earlier updates
+    async fn get_updates(offset: i64, timeout: u32) -> Result<Vec<api_type::ApiUpdate>, BotError> {
         // println!("get_updates() with offset {offset}");
-        return bot().get_updates_impl(offset).await;
+        return bot().get_updates_impl(offset, timeout).await;
     }
-    async fn get_updates_impl(&self, offset: i64) -> Result<Vec<api_type::ApiUpdate>, BotError> {
-        let mut update_params = api_type::GetUpdatesParams::default();
-        update_params.timeout = Some(1024);
-        update_params.offset = Some(offset);
+    async fn get_updates_impl(&self, offset: i64, timeout: u32) -> Result<Vec<api_type::ApiUpdate>, BotError> {
+        let update_params = api_type::GetUpdatesParams {
+            timeout: Some(timeout)
+            ,offset: Some(offset)
+            ,..Default::default()
+        };
         let params_str = serde_json::to_string(&update_params)?;
 
         let json_value = self.query_with_params("getUpdates", &params_str).await?;
@@ -170,15 +433,17 @@ impl TelegramBot {
 
         Ok(update_list)
     }
-    async fn send_message_imp(&self, chat_id: i64, text: &str)
+    async fn send_message_imp(&self, chat_id: i64, text: &str, options: &api_type::SendMessageOptions)
         -> Result<api_type::ApiMessage, BotError>
     {
         let send_message_params = api_type::SendMessageParams {
-            chat_id, text: &text
+            chat_id, text, options
         };
         let params_str = serde_json::to_string(&send_message_params)?;
 
-        let json_value = self.query_with_params("sendMessage", &params_str).await?;
+        let json_value = self.query_chat(chat_id, "sendMessage", || {
+            self.query_with_params("sendMessage", &params_str)
+        }).await?;
         // println!("send message got {json_value:?}");
 
         let api_message: api_type::ApiMessage = serde_json::from_value(json_value)?;
@@ -186,6 +451,64 @@ impl TelegramBot {
 
         Ok(api_message)
     }
+    async fn send_file_impl(
+        &self, chat_id: i64, file: &FileUpload, caption: Option<&str>, options: &api_type::SendMessageOptions
+    ) -> Result<api_type::ApiMessage, BotError>
+    {
+        let (method_name, field_name) = if file.is_photo() && file.data.len() <= MAX_PHOTO_SIZE {
+            ("sendPhoto", "photo")
+        } else {
+            ("sendDocument", "document")
+        };
+        let mut form = MultipartForm::new()?;
+        form.add_text("chat_id", &chat_id.to_string());
+        if let Some(caption) = caption {
+            form.add_text("caption", caption);
+        }
+        if let Some(parse_mode) = options.parse_mode {
+            form.add_text("parse_mode", parse_mode.as_str());
+        }
+        if let Some(disable_notification) = options.disable_notification {
+            form.add_text("disable_notification", &disable_notification.to_string());
+        }
+        if let Some(protect_content) = options.protect_content {
+            form.add_text("protect_content", &protect_content.to_string());
+        }
+        form.add_file(field_name, file);
+        let content_type = form.content_type();
+        let body = form.into_body();
+
+        let url = self.method_url(method_name);
+        let json_value = self.query_chat(chat_id, method_name, || {
+            self.https_query_multipart(&url, &content_type, body.clone())
+        }).await?;
+
+        let api_message: api_type::ApiMessage = serde_json::from_value(json_value)?;
+
+        Ok(api_message)
+    }
+    /// run query sending something to chat: waits for rate limiter and retries
+    /// after short flood control pauses
+    async fn query_chat<F, Fut>(&self, chat_id: i64, method_name: &str, query: F)
+        -> Result<serde_json::Value, BotError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<serde_json::Value, BotError>>,
+    {
+        loop {
+            self.rate_limiter.acquire(chat_id).await;
+            match query().await {
+                Err(err @ BotError::TelegramApi { retry_after: Some(retry_after), .. }) => {
+                    tracing::warn!("{method_name} to chat {chat_id} hit flood control, retry after {retry_after}s");
+                    self.rate_limiter.pause_chat(chat_id, retry_after);
+                    if retry_after > MAX_INLINE_FLOOD_WAIT_SECS {
+                        return Err(err);
+                    }
+                },
+                result => return result,
+            }
+        }
+    }
 
     async fn query(&self, method_name: &str)
         -> Result<serde_json::Value, BotError>
@@ -196,9 +519,12 @@ impl TelegramBot {
         &self, method_name: &str, params_str: &str
     ) -> Result<serde_json::Value, BotError>
     {
-        let url = format!("https://api.telegram.org/bot{}/{}", self.token, method_name);
+        let url = self.method_url(method_name);
         return self.https_query(&url, params_str).await;
     }
+    fn method_url(&self, method_name: &str) -> String {
+        format!("{}/bot{}/{}", self.api_url, self.token, method_name)
+    }
 
     async fn https_query(&self,url: &str, params_json_str: &str)
         -> Result<serde_json::Value, BotError>
@@ -209,41 +535,117 @@ impl TelegramBot {
             .header("content-type", "application/json")
             .body(Body::from(params_json_str.to_string()))?;
 
-        let resp = self.https_client.request(req).await?;
-        // println!("Status:\n{}", resp.status());
-        // println!("Headers:\n{:#?}", resp.headers());
+        return self.execute_query(req, url).await;
+    }
+    async fn https_query_multipart(&self, url: &str, content_type: &str, body: Vec<u8>)
+        -> Result<serde_json::Value, BotError>
+    {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(url)
+            .header("content-type", content_type)
+            .body(Body::from(body))?;
+
+        return self.execute_query(req, url).await;
+    }
+    async fn execute_query(&self, req: hyper::Request<Body>, url: &str)
+        -> Result<serde_json::Value, BotError>
+    {
+        // method name is the last url segment, url itself contains bot token
+        let method_name = url.rsplit('/').next().unwrap_or_default();
+        let started = std::time::Instant::now();
+        let result = self.execute_query_unmeasured(req, method_name).await;
+        let result_label = match &result {
+            Ok(_) => "ok".to_string(),
+            Err(BotError::TelegramApi { code, .. }) => code.to_string(),
+            Err(_) => "error".to_string(),
+        };
+        metrics::inc_counter(metrics::TELEGRAM_REQUESTS, &[("method", method_name), ("result", &result_label)]);
+        metrics::observe(metrics::TELEGRAM_REQUEST_DURATION, &[("method", method_name)],
+                         started.elapsed().as_secs_f64());
 
-        let body: Body = resp.into_body();
-        let body = to_bytes(body).await?;
-        // println!("Body:\n{}", String::from_utf8_lossy(&body));
+        result
+    }
+    async fn execute_query_unmeasured(&self, req: hyper::Request<Body>, method_name: &str)
+        -> Result<serde_json::Value, BotError>
+    {
+        let secs = match method_name {
+            "getUpdates" => self.poll_timeout as u64 + REQUEST_TIMEOUT_SECS,
+            _ => REQUEST_TIMEOUT_SECS,
+        };
+        let response = timeout(Duration::from_secs(secs), async {
+            let resp = self.https_client.request(req).await?;
+            // println!("Status:\n{}", resp.status());
+            // println!("Headers:\n{:#?}", resp.headers());
+            let status = resp.status();
+
+            let body: Body = resp.into_body();
+            let body = to_bytes(body).await?;
+            // println!("Body:\n{}", String::from_utf8_lossy(&body));
+            Ok::<_, BotError>((status, body))
+        }).await;
+        let (status, body) = match response {
+            Ok(response) => response?,
+            Err(_) => {
+                tracing::warn!("{method_name} got no answer in {secs}s");
+                return Err(BotError::RequestTimeout { method: method_name.to_string(), secs });
+            },
+        };
 
-        let result: api_type::QueryResult = serde_json::from_slice(&body)?;
-        if result.ok == false {
-            tracing::warn!("Query error:\nUrl: {url}\nBody: {params_json_str}\nreturned error:{result:?}");
+        let result: api_type::QueryResult = match serde_json::from_slice(&body) {
+            Ok(result) => result,
+            Err(_) if !status.is_success() => {
+                return Err(BotError::TelegramApi {
+                    code: status.as_u16() as i64,
+                    description: String::from_utf8_lossy(&body).to_string(),
+                    retry_after: None,
+                    migrate_to_chat_id: None,
+                });
+            },
+            Err(err) => return Err(err.into()),
+        };
+        if !result.ok || !status.is_success() {
+            // url and parameters are not logged: they contain bot token and webhook secret
+            tracing::warn!("{method_name} returned error: {:?} {:?}", result.error_code, result.description);
+            let parameters = result.parameters.unwrap_or_default();
+            return Err(BotError::TelegramApi {
+                code: result.error_code.unwrap_or(status.as_u16() as i64),
+                description: result.description.unwrap_or_default(),
+                retry_after: parameters.retry_after,
+                migrate_to_chat_id: parameters.migrate_to_chat_id,
+            });
         }
-        // FIXME: return error on non 2xx status
         // println!("QueryResult object {result:?}");
 
-        return Ok(result.result.unwrap());
+        result.result.ok_or_else(|| BotError::TelegramApi {
+            code: status.as_u16() as i64,
+            description: "response without result".to_string(),
+            retry_after: None,
+            migrate_to_chat_id: None,
+        })
     }
 }
 
 
 type HttpsClient = hyper::Client<HttpsConnector<HttpConnector>>;
 
-fn create_https_client() -> HttpsClient {
+fn create_https_client(allow_http: bool) -> HttpsClient {
     let tls = rustls::ClientConfig::builder()
         .with_safe_defaults()
         .with_native_roots()
         .with_no_client_auth();
     let https_connector = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_tls_config(tls)
-        .https_only()
-        // .https_or_http()
+        .with_tls_config(tls);
+    let https_connector = if allow_http {
+        https_connector.https_or_http()
+    } else {
+        https_connector.https_only()
+    };
+    let https_connector = https_connector
         .enable_http1()
         .build();
 
-    let client = client::Client::builder().build(https_connector);
+    
 
-    return client;
+    client::Client::builder().build(https_connector)
 }
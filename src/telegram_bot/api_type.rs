@@ -10,7 +10,14 @@ pub struct QueryResult {
     pub ok: bool,
     pub result:      Option<serde_json::Value>, // result on success
     pub description: Option<String>, // human-readable description of the result
-    // pub parameters: Option<ResponseParameters>,
+    pub error_code: Option<i64>,
+    pub parameters: Option<ResponseParameters>,
+}
+
+#[derive(Serialize, Deserialize,Debug,Default)]
+pub struct ResponseParameters { // https://core.telegram.org/bots/api#responseparameters
+    pub migrate_to_chat_id: Option<i64>, // the group has been migrated to a supergroup with this id
+    pub retry_after: Option<u64>, // seconds left to wait before the request can be repeated
 }
 
 #[derive(Serialize, Deserialize,Debug)]
@@ -50,6 +57,37 @@ pub struct ApiUpdate { // https://core.telegram.org/bots/api#update
 pub struct SendMessageParams<'a> { // https://core.telegram.org/bots/api#sendmessage
     pub chat_id: i64,
     pub text: &'a str,
+    #[serde(flatten)]
+    pub options: &'a SendMessageOptions,
+}
+
+/// optional sendMessage parameters exposed to api clients
+#[derive(Serialize, Deserialize,Debug,Default,Clone,PartialEq)]
+pub struct SendMessageOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<ParseMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disable_notification: Option<bool>, // send silently
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disable_web_page_preview: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protect_content: Option<bool>, // protect from forwarding and saving
+}
+
+#[derive(Serialize, Deserialize,Debug,Clone,Copy,PartialEq)]
+pub enum ParseMode { // https://core.telegram.org/bots/api#formatting-options
+    MarkdownV2,
+    HTML,
+    Markdown,
+}
+impl ParseMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParseMode::MarkdownV2 => "MarkdownV2",
+            ParseMode::HTML => "HTML",
+            ParseMode::Markdown => "Markdown",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize,Debug,Default)]
@@ -81,6 +119,13 @@ pub struct ApiBotCommand<'a> { // https://core.telegram.org/bots/api#botcommand
     pub description: &'a str,
 }
 
+#[derive(Serialize, Deserialize,Debug,Default)]
+pub struct ApiWebhookInfo { // https://core.telegram.org/bots/api#webhookinfo
+    pub url: String,
+    pub pending_update_count: i64,
+    pub last_error_date: Option<i64>,
+    pub last_error_message: Option<String>,
+}
 #[derive(Serialize,Debug,Default)]
 pub struct SetWebhookParams<'a> { // https://core.telegram.org/bots/api#setwebhook
     pub url: &'a str,
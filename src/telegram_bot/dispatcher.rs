@@ -0,0 +1,158 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Worker pool processing incoming updates.
+//!
+//! Updates of one chat always go to the same worker, so they are processed in order,
+//! while different chats are processed concurrently.
+//!
+//! Chats are assigned to workers by `chat_id % WORKER_COUNT`, so a chat whose update is slow
+//! (rate limited answer, slow database) delays every other chat of its worker meanwhile.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+
+use crate::db;
+use crate::error::BotError;
+use crate::telegram_bot::{api_type::ApiUpdate, updates_handler};
+
+/// bot commands are short and answers go through shared rate limiter anyway,
+/// few workers are enough
+const WORKER_COUNT: usize = 4;
+/// updates waiting in one worker queue, webhook update is rejected when queue is full
+/// (telegram delivers it again later), poller waits
+const QUEUE_SIZE: usize = 256;
+
+struct Job {
+    /// `None` is flush marker
+    update: Option<ApiUpdate>,
+    /// signaled when update processed
+    done: Option<oneshot::Sender<()>>,
+}
+
+static WORKERS: OnceCell<Vec<mpsc::Sender<Job>>> = OnceCell::new();
+/// unix time worker started processing current update, 0 when idle
+static BUSY_SINCE: Lazy<Vec<AtomicI64>> = Lazy::new(|| (0..WORKER_COUNT).map(|_| AtomicI64::new(0)).collect());
+
+pub fn init() {
+    WORKERS.get_or_init(|| {
+        (0..WORKER_COUNT).map(|worker_id| {
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(run_worker(worker_id, receiver));
+            sender
+        }).collect()
+    });
+}
+
+async fn run_worker(worker_id: usize, mut receiver: mpsc::Receiver<Job>) {
+    while let Some(job) = receiver.recv().await {
+        if let Some(update) = job.update {
+            let span = tracing::info_span!("update", update_id = update.update_id, chat_id = chat_id_of(&update),
+                                           worker_id);
+            BUSY_SINCE[worker_id].store(db::unix_time_current(), Ordering::Relaxed);
+            if let Err(err) = updates_handler::handle_update(update).instrument(span.clone()).await {
+                span.in_scope(|| tracing::error!("failed processing update: {err:?}"));
+            }
+            BUSY_SINCE[worker_id].store(0, Ordering::Relaxed);
+        }
+        if let Some(done) = job.done {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn chat_id_of(update: &ApiUpdate) -> i64 {
+    update.message.as_ref().map(|message| message.chat.id).unwrap_or(0)
+}
+
+fn worker_for(update: &ApiUpdate) -> Result<&'static mpsc::Sender<Job>, BotError> {
+    let workers = WORKERS.get().ok_or(BotError::UpdateQueueClosed)?;
+
+    Ok(&workers[chat_id_of(update).rem_euclid(workers.len() as i64) as usize])
+}
+
+/// queue update for processing, does not wait if worker queue is full
+pub fn dispatch(update: ApiUpdate) -> Result<(), BotError> {
+    let worker = worker_for(&update)?;
+    worker.try_send(Job { update: Some(update), done: None }).map_err(|err| match err {
+        mpsc::error::TrySendError::Full(_) => BotError::UpdateQueueFull,
+        mpsc::error::TrySendError::Closed(_) => BotError::UpdateQueueClosed,
+    })
+}
+
+/// process updates and wait until all of them are done
+pub async fn dispatch_and_wait(updates: Vec<ApiUpdate>) -> Result<(), BotError> {
+    let mut done_list = Vec::with_capacity(updates.len());
+    for update in updates {
+        let worker = worker_for(&update)?;
+        let (done, done_receiver) = oneshot::channel();
+        worker.send(Job { update: Some(update), done: Some(done) }).await
+            .map_err(|_| BotError::UpdateQueueClosed)?;
+        done_list.push(done_receiver);
+    }
+    for done in done_list {
+        done.await.map_err(|_| BotError::UpdateQueueClosed)?;
+    }
+
+    Ok(())
+}
+
+/// updates waiting in worker queues
+pub fn queue_depth() -> usize {
+    let Some(workers) = WORKERS.get() else { return 0 };
+    workers.iter().map(|worker| worker.max_capacity() - worker.capacity()).sum()
+}
+
+/// seconds the longest running update is processed, `None` if all workers are idle
+pub fn longest_busy_secs(now: i64) -> Option<i64> {
+    BUSY_SINCE.iter()
+        .map(|since| since.load(Ordering::Relaxed))
+        .filter(|since| *since != 0)
+        .min()
+        .map(|since| now - since)
+}
+
+/// wait until updates queued before this call are processed
+pub async fn flush() {
+    let Some(workers) = WORKERS.get() else { return };
+    let mut done_list = Vec::with_capacity(workers.len());
+    for worker in workers {
+        let (done, done_receiver) = oneshot::channel();
+        if worker.send(Job { update: None, done: Some(done) }).await.is_ok() {
+            done_list.push(done_receiver);
+        }
+    }
+    for done in done_list {
+        let _ = done.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_chat_updates_in_order() {
+        let _env = crate::integration_tests::env();
+        crate::integration_tests::RUNTIME.block_on(async {
+            let chat_id = 204;
+            let updates: Vec<ApiUpdate> = ["/new_token a", "/revoke_token a", "/new_token b"].iter()
+                .enumerate()
+                .map(|(index, text)| serde_json::from_value(serde_json::json!({
+                    "update_id": 7000 + index,
+                    "message": {"message_id": index, "date": 0, "chat": {"id": chat_id}, "text": text}
+                })).unwrap())
+                .collect();
+            dispatch_and_wait(updates).await.unwrap();
+
+            let labels: Vec<String> = db::list_sessions(chat_id).await.unwrap()
+                .into_iter().map(|session| session.label).collect();
+            assert_eq!(labels, vec!["b".to_string()]);
+        });
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Split long text into parts fitting telegram message size limit.
+//!
+//! Text is cut on line boundaries if possible (then on spaces), never inside html tag,
+//! html entity, markdown escape sequence or link (unless it alone does not fit in a part).
+//! Formatting entities open at the cut are closed at the end of the part and reopened
+//! in the next one. Parts are numbered "1/3", "2/3"...
+
+use crate::telegram_bot::api_type::ParseMode;
+
+/// telegram limit for message text, in UTF-16 code units
+pub const MAX_MESSAGE_LEN: usize = 4096;
+/// room left for "12/34\n" part header
+const PART_HEADER_RESERVE: usize = 16;
+/// longer markdown links can be cut
+const MAX_LINK_LEN: usize = 1024;
+
+/// split text for sending as several messages, short text returned as is
+pub fn split_message(text: &str, parse_mode: Option<ParseMode>) -> Vec<String> {
+    split_message_with_limit(text, parse_mode, MAX_MESSAGE_LEN)
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Text,
+    Open,   // html opening tag
+    Close,  // html closing tag
+    Toggle, // markdown entity marker, opens or closes entity
+}
+
+#[derive(Debug)]
+struct Atom<'a> {
+    text: &'a str,
+    token: Token,
+    key: &'a str, // tag name or markdown marker
+}
+impl<'a> Atom<'a> {
+    fn text(text: &'a str) -> Atom<'a> {
+        Atom { text, token: Token::Text, key: "" }
+    }
+}
+
+/// formatting entities open at some position of text
+#[derive(Debug, Clone, Default)]
+struct MarkupState<'a> {
+    open: Vec<(&'a str, &'a str)>, // (key, opening text)
+}
+impl<'a> MarkupState<'a> {
+    fn apply(&mut self, atom: &Atom<'a>) {
+        let position = self.open.iter().rposition(|(key, _)| *key == atom.key);
+        match (atom.token, position) {
+            (Token::Text, _) => {},
+            (Token::Open, _) | (Token::Toggle, None) => self.open.push((atom.key, atom.text)),
+            (Token::Close | Token::Toggle, Some(position)) => self.open.truncate(position),
+            (Token::Close, None) => {},
+        }
+    }
+    fn reopening(&self) -> String {
+        self.open.iter().map(|(_, opening)| *opening).collect()
+    }
+    fn closing(&self, parse_mode: Option<ParseMode>) -> String {
+        self.open.iter().rev()
+            .map(|(key, _)| match parse_mode {
+                Some(ParseMode::HTML) => format!("</{key}>"),
+                _ => key.to_string(),
+            })
+            .collect()
+    }
+}
+
+fn tokenize(text: &str, parse_mode: Option<ParseMode>) -> Vec<Atom<'_>> {
+    match parse_mode {
+        None => text.char_indices().map(|(i, c)| Atom::text(&text[i..i + c.len_utf8()])).collect(),
+        Some(ParseMode::HTML) => tokenize_html(text),
+        Some(ParseMode::MarkdownV2) => tokenize_markdown(text, true),
+        Some(ParseMode::Markdown) => tokenize_markdown(text, false),
+    }
+}
+
+fn first_char_len(text: &str) -> usize {
+    text.chars().next().map(char::len_utf8).unwrap_or(0)
+}
+
+fn tokenize_html(text: &str) -> Vec<Atom<'_>> {
+    let mut atoms = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with('<') {
+            if let Some(end) = rest.find('>') {
+                let tag = &rest[..=end];
+                let (token, name) = match tag.strip_prefix("</") {
+                    Some(name) => (Token::Close, name),
+                    None => (Token::Open, &tag[1..]),
+                };
+                let name_len = name.find(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+                    .unwrap_or(name.len());
+                atoms.push(Atom { text: tag, token, key: &name[..name_len] });
+                i += tag.len();
+                continue;
+            }
+        }
+        if rest.starts_with('&') {
+            if let Some(end) = rest[..rest.len().min(12)].find(';') {
+                if end > 1 && rest[1..end].chars().all(|c| c.is_ascii_alphanumeric() || c == '#') {
+                    atoms.push(Atom::text(&rest[..=end]));
+                    i += end + 1;
+                    continue;
+                }
+            }
+        }
+        let char_len = first_char_len(rest);
+        atoms.push(Atom::text(&rest[..char_len]));
+        i += char_len;
+    }
+    atoms
+}
+
+/// length of `[text](url)` link at the start of text
+fn markdown_link_len(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    let mut in_url = false;
+    let mut prev = ' ';
+    while let Some((i, c)) = chars.next() {
+        if i > MAX_LINK_LEN || c == '\n' {
+            return None;
+        }
+        if prev != '\\' {
+            if !in_url && c == ']' {
+                if chars.peek().map(|(_, c)| *c) != Some('(') {
+                    return None;
+                }
+                in_url = true;
+            } else if in_url && c == ')' {
+                return Some(i + 1);
+            }
+        }
+        prev = if prev == '\\' { ' ' } else { c };
+    }
+    None
+}
+
+fn tokenize_markdown(text: &str, v2: bool) -> Vec<Atom<'_>> {
+    let mut atoms = Vec::new();
+    // inside code only closing marker is special
+    let mut code_marker: Option<&str> = None;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let atom = if rest.starts_with('\\') && rest.len() > 1 {
+            Atom::text(&rest[..1 + first_char_len(&rest[1..])])
+        } else if let Some(marker) = code_marker {
+            if rest.starts_with(marker) {
+                code_marker = None;
+                Atom { text: &rest[..marker.len()], token: Token::Toggle, key: marker }
+            } else {
+                Atom::text(&rest[..first_char_len(rest)])
+            }
+        } else if let Some(after_marker) = rest.strip_prefix("```") {
+            // opening with language: ```python\n
+            let language_len = after_marker.find(|c: char| !c.is_ascii_alphanumeric() && c != '+' && c != '-')
+                .unwrap_or(after_marker.len());
+            let opening_len = if after_marker[language_len..].starts_with('\n') { 3 + language_len + 1 } else { 3 };
+            code_marker = Some("```");
+            Atom { text: &rest[..opening_len], token: Token::Toggle, key: "```" }
+        } else if rest.starts_with('`') {
+            code_marker = Some("`");
+            Atom { text: &rest[..1], token: Token::Toggle, key: "`" }
+        } else if v2 && (rest.starts_with("__") || rest.starts_with("||")) {
+            Atom { text: &rest[..2], token: Token::Toggle, key: &rest[..2] }
+        } else if rest.starts_with('*') || rest.starts_with('_') || (v2 && rest.starts_with('~')) {
+            Atom { text: &rest[..1], token: Token::Toggle, key: &rest[..1] }
+        } else if let Some(link_len) = rest.strip_prefix('[').and_then(markdown_link_len) {
+            Atom::text(&rest[..1 + link_len])
+        } else {
+            Atom::text(&rest[..first_char_len(rest)])
+        };
+        i += atom.text.len();
+        atoms.push(atom);
+    }
+    atoms
+}
+
+/// atom which does not fit in part (html tag with very long attribute) is split to characters,
+/// part is cut inside it
+fn split_oversized_atoms(atoms: Vec<Atom<'_>>, max_len: usize) -> Vec<Atom<'_>> {
+    let mut result = Vec::with_capacity(atoms.len());
+    for atom in atoms {
+        if utf16_len(atom.text) <= max_len {
+            result.push(atom);
+            continue;
+        }
+        result.extend(atom.text.char_indices().map(|(i, c)| Atom::text(&atom.text[i..i + c.len_utf8()])));
+    }
+    result
+}
+
+/// possible end of part
+#[derive(Debug, Clone)]
+struct Cut<'a> {
+    end: usize, // atom index
+    len: usize,
+    state: MarkupState<'a>,
+}
+
+fn split_message_with_limit(text: &str, parse_mode: Option<ParseMode>, limit: usize) -> Vec<String> {
+    if utf16_len(text) <= limit {
+        return vec![text.to_string()];
+    }
+    let budget = limit - PART_HEADER_RESERVE;
+    // room for reopened and closed entities
+    let atoms = split_oversized_atoms(tokenize(text, parse_mode), budget / 2);
+    let mut parts: Vec<String> = Vec::new();
+    let mut start = 0;
+    let mut start_state = MarkupState::default();
+    while start < atoms.len() {
+        let reopening = start_state.reopening();
+        let mut state = start_state.clone();
+        let mut len = utf16_len(&reopening);
+        let mut newline_cut: Option<Cut> = None;
+        let mut space_cut: Option<Cut> = None;
+        let mut end = start;
+        while end < atoms.len() {
+            let atom = &atoms[end];
+            let mut next_state = state.clone();
+            next_state.apply(atom);
+            let atom_len = utf16_len(atom.text);
+            if end > start && len + atom_len + utf16_len(&next_state.closing(parse_mode)) > budget {
+                break;
+            }
+            len += atom_len;
+            state = next_state;
+            end += 1;
+            if atom.token == Token::Text && atom.text == "\n" {
+                newline_cut = Some(Cut { end, len, state: state.clone() });
+            } else if atom.token == Token::Text && atom.text.trim().is_empty() {
+                space_cut = Some(Cut { end, len, state: state.clone() });
+            }
+        }
+        let hard_cut = Cut { end, len, state };
+        let cut = if end >= atoms.len() {
+            hard_cut
+        } else {
+            // prefer line boundary unless it leaves the part almost empty
+            newline_cut.clone().filter(|cut| cut.len * 2 >= budget)
+                .or(space_cut)
+                .or(newline_cut)
+                .unwrap_or(hard_cut)
+        };
+        let content: String = atoms[start..cut.end].iter().map(|atom| atom.text).collect();
+        if !content.trim().is_empty() {
+            parts.push(format!("{reopening}{content}{}", cut.state.closing(parse_mode)));
+        }
+        start = cut.end;
+        start_state = cut.state;
+    }
+
+    let count = parts.len();
+    if count > 1 {
+        for (i, part) in parts.iter_mut().enumerate() {
+            part.insert_str(0, &format!("{}/{count}\n", i + 1));
+        }
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_message_not_split() {
+        assert_eq!(split_message("hello", None), vec!["hello"]);
+        assert_eq!(split_message(&"x".repeat(MAX_MESSAGE_LEN), None).len(), 1);
+    }
+
+    #[test]
+    fn test_split_on_lines() {
+        let text = "first line\nsecond line\nthird line\nfourth line\n";
+        let parts = split_message_with_limit(text, None, 40);
+        assert_eq!(parts, vec!["1/2\nfirst line\nsecond line\n", "2/2\nthird line\nfourth line\n"]);
+    }
+
+    #[test]
+    fn test_long_line_split_on_spaces() {
+        let text = "aaaa bbbb cccc dddd eeee ffff gggg";
+        let parts = split_message_with_limit(text, None, 30);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| utf16_len(part) <= 30));
+        let joined: String = parts.iter().map(|part| part.split_once('\n').unwrap().1).collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn test_utf16_length() {
+        // every emoji is 2 UTF-16 code units
+        let text = "😀".repeat(30);
+        let parts = split_message_with_limit(&text, None, 36);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| utf16_len(part) <= 36));
+    }
+
+    #[test]
+    fn test_html_tags_reopened() {
+        let text = "<b>job</b>\n<pre><code class=\"language-log\">line 1\nline 2\nline 3 &amp; more\nline 4\n</code></pre>";
+        let parts = split_message_with_limit(text, Some(ParseMode::HTML), 70);
+        assert!(parts.len() > 1, "{parts:?}");
+        for part in &parts {
+            assert!(utf16_len(part) <= 70);
+            assert_eq!(part.matches("<pre>").count(), part.matches("</pre>").count(), "{part}");
+            assert_eq!(part.matches("<code").count(), part.matches("</code>").count(), "{part}");
+            assert!(!part.contains("&am\n"));
+        }
+        assert!(parts[1].contains("<pre><code class=\"language-log\">"));
+    }
+
+    #[test]
+    fn test_markdown_code_block_reopened() {
+        let text = "*build* failed\n```log\nerror 1\nerror 2\nerror 3\nerror 4\n```\ndone\\!";
+        let parts = split_message_with_limit(text, Some(ParseMode::MarkdownV2), 40);
+        assert!(parts.len() > 1, "{parts:?}");
+        for part in &parts {
+            assert!(utf16_len(part) <= 40);
+            assert_eq!(part.matches("```").count() % 2, 0, "{part}");
+        }
+        assert!(parts[1].contains("```log\n"));
+    }
+
+    #[test]
+    fn test_oversized_atom_split() {
+        let text = format!("<a href=\"https://example.com/{}\">link</a>", "x".repeat(5000));
+        let parts = split_message(&text, Some(ParseMode::HTML));
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| utf16_len(part) <= MAX_MESSAGE_LEN));
+        let joined: String = parts.iter().map(|part| part.split_once('\n').unwrap().1).collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn test_markdown_escape_and_link_not_broken() {
+        let atoms = tokenize_markdown("a\\*b [link](https://x.y/\\)z) c", true);
+        let texts: Vec<&str> = atoms.iter().map(|atom| atom.text).collect();
+        assert!(texts.contains(&"\\*"));
+        assert!(texts.contains(&"[link](https://x.y/\\)z)"));
+        assert!(atoms.iter().all(|atom| atom.token == Token::Text));
+    }
+}
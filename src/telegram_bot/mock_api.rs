@@ -0,0 +1,238 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Local stand-in for Telegram Bot API used by tests.
+//!
+//! Serves `http://127.0.0.1:<port>/bot<token>/<method>`, records every call and answers
+//! like the real api. Errors for chosen chats and methods can be scripted
+//! with `fail_chat()` and `fail_method()`.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use tokio::time::{sleep, Duration};
+
+/// error returned by mock instead of normal result
+#[derive(Debug, Clone)]
+pub struct MockError {
+    pub code: u16,
+    pub description: String,
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    calls: Vec<(String, Value)>,
+    /// scripted results of next messages to chat, `None` is normal delivery
+    chat_errors: HashMap<i64, VecDeque<Option<MockError>>>,
+    /// errors of next calls of api method
+    method_errors: HashMap<String, VecDeque<MockError>>,
+    updates: VecDeque<Value>,
+    next_message_id: i64,
+}
+
+#[derive(Clone)]
+pub struct MockApi {
+    pub url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockApi {
+    /// start mock server on random local port
+    pub async fn start() -> MockApi {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let app = Router::new()
+            .route("/:bot/:method", post(handle_method))
+            .with_state(state.clone());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        MockApi { url, state }
+    }
+    /// parameters of all calls of api method
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.state.lock().unwrap().calls.iter()
+            .filter(|(name, _)| name == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+    /// texts sent to chat with sendMessage
+    pub fn sent_texts(&self, chat_id: i64) -> Vec<String> {
+        self.calls("sendMessage").iter()
+            .filter(|params| params["chat_id"] == chat_id)
+            .map(|params| params["text"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+    /// wait until `count` texts sent to chat (or timeout)
+    pub async fn wait_sent_texts(&self, chat_id: i64, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let texts = self.sent_texts(chat_id);
+            if texts.len() >= count {
+                return texts;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        self.sent_texts(chat_id)
+    }
+    /// next `times` messages to chat will fail with `error`
+    pub fn fail_chat(&self, chat_id: i64, times: usize, error: MockError) {
+        let mut state = self.state.lock().unwrap();
+        let errors = state.chat_errors.entry(chat_id).or_default();
+        for _ in 0..times {
+            errors.push_back(Some(error.clone()));
+        }
+    }
+    /// message to chat fails with `error` after `delivered` messages went through
+    pub fn fail_chat_after(&self, chat_id: i64, delivered: usize, error: MockError) {
+        let mut state = self.state.lock().unwrap();
+        let errors = state.chat_errors.entry(chat_id).or_default();
+        errors.extend(std::iter::repeat_n(None, delivered));
+        errors.push_back(Some(error));
+    }
+    /// next `times` calls of api method will fail with `error`
+    pub fn fail_method(&self, method: &str, times: usize, error: MockError) {
+        let mut state = self.state.lock().unwrap();
+        let errors = state.method_errors.entry(method.to_string()).or_default();
+        for _ in 0..times {
+            errors.push_back(error.clone());
+        }
+    }
+    /// update returned by next getUpdates
+    pub fn push_update(&self, update: Value) {
+        self.state.lock().unwrap().updates.push_back(update);
+    }
+    /// wait until api method called `count` times (or timeout), returns calls
+    pub async fn wait_calls(&self, method: &str, count: usize) -> Vec<Value> {
+        for _ in 0..100 {
+            let calls = self.calls(method);
+            if calls.len() >= count {
+                return calls;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        self.calls(method)
+    }
+}
+
+async fn handle_method(
+    State(state): State<Arc<Mutex<MockState>>>,
+    Path((_bot, method)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<Value>) {
+    let content_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let params = match content_type.split_once("boundary=") {
+        Some((_, boundary)) => parse_multipart(&body, boundary),
+        None => serde_json::from_slice(&body).unwrap_or(Value::Null),
+    };
+    let method_error = {
+        let mut state = state.lock().unwrap();
+        state.calls.push((method.clone(), params.clone()));
+        state.method_errors.get_mut(&method).and_then(|errors| errors.pop_front())
+    };
+
+    let result = match method_error {
+        Some(err) => Err(err),
+        None => match method.as_str() {
+            "getMe" => Ok(json!({"id": 1, "is_bot": true, "first_name": "mock", "username": "mock_bot"})),
+            "sendMessage" | "sendPhoto" | "sendDocument" => send_message(&state, &params),
+            "getUpdates" => Ok(get_updates(&state).await),
+            "setWebhook" | "deleteWebhook" | "setMyCommands" => Ok(json!(true)),
+            "getWebhookInfo" => Ok(json!({"url": "https://example.com/webhook", "pending_update_count": 0})),
+            _ => Err(MockError { code: 404, description: "Not Found".to_string(), retry_after: None }),
+        },
+    };
+    match result {
+        Ok(result) => (StatusCode::OK, Json(json!({"ok": true, "result": result}))),
+        Err(err) => {
+            let mut body = json!({"ok": false, "error_code": err.code, "description": err.description});
+            if let Some(retry_after) = err.retry_after {
+                body["parameters"] = json!({"retry_after": retry_after});
+            }
+            (StatusCode::from_u16(err.code).unwrap(), Json(body))
+        },
+    }
+}
+
+fn send_message(state: &Mutex<MockState>, params: &Value) -> Result<Value, MockError> {
+    let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+    let mut state = state.lock().unwrap();
+    if let Some(Some(err)) = state.chat_errors.get_mut(&chat_id).and_then(|errors| errors.pop_front()) {
+        return Err(err);
+    }
+    state.next_message_id += 1;
+
+    Ok(json!({
+        "message_id": state.next_message_id,
+        "date": 0,
+        "chat": {"id": chat_id},
+        "text": params["text"],
+    }))
+}
+
+async fn get_updates(state: &Mutex<MockState>) -> Value {
+    let updates: Vec<Value> = state.lock().unwrap().updates.drain(..).collect();
+    if updates.is_empty() {
+        // short long-polling
+        sleep(Duration::from_millis(100)).await;
+    }
+    json!(updates)
+}
+
+fn find_bytes(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|window| window == pattern)
+}
+
+/// form fields as json object, files recorded as {"file_name", "content_type", "size"}
+fn parse_multipart(body: &[u8], boundary: &str) -> Value {
+    let delimiter = format!("--{boundary}");
+    let mut params = serde_json::Map::new();
+    let mut rest = body;
+    while let Some(start) = find_bytes(rest, delimiter.as_bytes()) {
+        rest = &rest[start + delimiter.len()..];
+        if rest.starts_with(b"--") {
+            break;
+        }
+        let Some(headers_end) = find_bytes(rest, b"\r\n\r\n") else { break };
+        let Some(part_end) = find_bytes(rest, delimiter.as_bytes()) else { break };
+        let part_headers = String::from_utf8_lossy(&rest[..headers_end]).to_string();
+        let content = &rest[headers_end + 4..part_end - 2]; // without trailing \r\n
+        let header_value = |name: &str| part_headers.split(&format!("{name}=\""))
+            .nth(1)
+            .and_then(|tail| tail.split('"').next())
+            .map(str::to_string);
+        let name = header_value("name").unwrap_or_default();
+        let value = match header_value("filename") {
+            Some(file_name) => {
+                let content_type = part_headers.split("Content-Type: ").nth(1)
+                    .map(|tail| tail.trim().to_string());
+                json!({"file_name": file_name, "content_type": content_type, "size": content.len()})
+            },
+            None => {
+                let text = String::from_utf8_lossy(content).to_string();
+                match text.parse::<i64>() {
+                    Ok(number) if name == "chat_id" => json!(number),
+                    _ => json!(text),
+                }
+            },
+        };
+        params.insert(name, value);
+        rest = &rest[part_end..];
+    }
+    Value::Object(params)
+}
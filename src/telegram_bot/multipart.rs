@@ -0,0 +1,86 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! multipart/form-data request body for Bot API methods uploading files
+
+use crate::error::BotError;
+use crate::random;
+
+/// file received from api client
+#[derive(Debug)]
+pub struct FileUpload {
+    pub file_name: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl FileUpload {
+    /// content type detected by file signature, falls back to type sent by client
+    pub fn detect_content_type(data: &[u8], declared: Option<&str>) -> String {
+        let detected = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some("image/png")
+        } else if data.starts_with(b"\xFF\xD8\xFF") {
+            Some("image/jpeg")
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some("image/gif")
+        } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            Some("image/webp")
+        } else if data.starts_with(b"%PDF-") {
+            Some("application/pdf")
+        } else {
+            None
+        };
+        match (detected, declared) {
+            (Some(detected), _) => detected.to_string(),
+            // client can not claim image type for data without image signature
+            (None, Some(declared)) if !declared.starts_with("image/") => declared.to_string(),
+            _ => "application/octet-stream".to_string(),
+        }
+    }
+    /// telegram shows it as photo (sendPhoto accepts jpeg, png and webp)
+    pub fn is_photo(&self) -> bool {
+        matches!(self.content_type.as_str(), "image/jpeg" | "image/png" | "image/webp")
+    }
+}
+
+pub struct MultipartForm {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl MultipartForm {
+    pub fn new() -> Result<MultipartForm, BotError> {
+        let mut boundary_buf: [u8; 16] = [0; 16];
+        random::gen_random(&mut boundary_buf[..])?;
+        let boundary = format!("notify-me-bot-{}", hex::encode(boundary_buf));
+
+        Ok(MultipartForm { boundary, body: Vec::new() })
+    }
+    pub fn add_text(&mut self, name: &str, value: &str) {
+        self.body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n",
+            self.boundary
+        ).as_bytes());
+    }
+    pub fn add_file(&mut self, name: &str, file: &FileUpload) {
+        let file_name: String = file.file_name.chars()
+            .map(|c| if c == '"' || c == '\\' || c.is_control() { '_' } else { c })
+            .collect();
+        self.body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{file_name}\"\r\n\
+            Content-Type: {}\r\n\r\n",
+            self.boundary, file.content_type
+        ).as_bytes());
+        self.body.extend_from_slice(&file.data);
+        self.body.extend_from_slice(b"\r\n");
+    }
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+    pub fn into_body(mut self) -> Vec<u8> {
+        self.body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Token bucket limiter for outgoing messages.
+//!
+//! Telegram allows about 30 messages per second overall, about 1 message per second
+//! to one private chat and 20 messages per minute to one group
+//! (https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this).
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+const GLOBAL_RATE: f64 = 30.0;
+const GLOBAL_BURST: f64 = 30.0;
+const PRIVATE_CHAT_RATE: f64 = 1.0;
+const GROUP_CHAT_RATE: f64 = 20.0 / 60.0;
+const CHAT_BURST: f64 = 3.0;
+/// idle chat buckets are dropped when map grows above this size
+const MAX_TRACKED_CHATS: usize = 1024;
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64, // tokens per second
+    tokens: f64,
+    updated_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket { capacity, rate, tokens: capacity, updated_at: now, paused_until: None }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated_at = now;
+        if matches!(self.paused_until, Some(until) if until <= now) {
+            self.paused_until = None;
+        }
+    }
+    /// time to wait until one token available
+    fn wait_time(&self, now: Instant) -> Duration {
+        if let Some(until) = self.paused_until {
+            return until.saturating_duration_since(now);
+        }
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+    }
+    fn is_full(&self) -> bool {
+        self.paused_until.is_none() && self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    global: TokenBucket,
+    chats: HashMap<i64, TokenBucket>,
+}
+
+impl LimiterState {
+    fn new(now: Instant) -> LimiterState {
+        LimiterState { global: TokenBucket::new(GLOBAL_BURST, GLOBAL_RATE, now), chats: HashMap::new() }
+    }
+    fn chat_bucket(&mut self, chat_id: i64, now: Instant) -> &mut TokenBucket {
+        if self.chats.len() >= MAX_TRACKED_CHATS && !self.chats.contains_key(&chat_id) {
+            self.chats.retain(|_, bucket| { bucket.refill(now); !bucket.is_full() });
+        }
+        // negative ids are groups and channels
+        let rate = if chat_id < 0 { GROUP_CHAT_RATE } else { PRIVATE_CHAT_RATE };
+        self.chats.entry(chat_id).or_insert_with(|| TokenBucket::new(CHAT_BURST, rate, now))
+    }
+    /// take tokens for one message to chat or return time to wait
+    fn try_acquire(&mut self, chat_id: i64, now: Instant) -> Result<(), Duration> {
+        self.global.refill(now);
+        let global_wait = self.global.wait_time(now);
+        let chat_bucket = self.chat_bucket(chat_id, now);
+        chat_bucket.refill(now);
+        let wait = global_wait.max(chat_bucket.wait_time(now));
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        chat_bucket.tokens -= 1.0;
+        self.global.tokens -= 1.0;
+
+        Ok(())
+    }
+    fn pause_chat(&mut self, chat_id: i64, duration: Duration, now: Instant) {
+        let bucket = self.chat_bucket(chat_id, now);
+        bucket.paused_until = Some(now + duration);
+        bucket.tokens = 0.0;
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter { state: Mutex::new(LimiterState::new(Instant::now())) }
+    }
+    /// wait until message to chat can be sent without hitting telegram limits
+    pub async fn acquire(&self, chat_id: i64) {
+        loop {
+            let result = self.state.lock().unwrap().try_acquire(chat_id, Instant::now());
+            match result {
+                Ok(()) => return,
+                Err(wait) => sleep(wait).await,
+            }
+        }
+    }
+    /// stop sending to chat for `retry_after` seconds (got 429 from telegram)
+    pub fn pause_chat(&self, chat_id: i64, retry_after: u64) {
+        self.state.lock().unwrap().pause_chat(chat_id, Duration::from_secs(retry_after), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_burst_and_refill() {
+        let start = Instant::now();
+        let mut state = LimiterState::new(start);
+        for _ in 0..CHAT_BURST as usize {
+            assert!(state.try_acquire(1, start).is_ok());
+        }
+        let wait = state.try_acquire(1, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        // other chat is not affected
+        assert!(state.try_acquire(2, start).is_ok());
+        assert!(state.try_acquire(1, start + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let start = Instant::now();
+        let mut state = LimiterState::new(start);
+        for chat_id in 0..GLOBAL_BURST as i64 {
+            assert!(state.try_acquire(chat_id, start).is_ok());
+        }
+        assert!(state.try_acquire(1000, start).is_err());
+        assert!(state.try_acquire(1000, start + Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn test_pause_chat() {
+        let start = Instant::now();
+        let mut state = LimiterState::new(start);
+        state.pause_chat(5, Duration::from_secs(7), start);
+        assert_eq!(state.try_acquire(5, start).unwrap_err(), Duration::from_secs(7));
+        assert!(state.try_acquire(6, start).is_ok());
+        assert!(state.try_acquire(5, start + Duration::from_secs(8)).is_ok());
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! How bot receives updates: webhook, long polling or automatic choice.
+//!
+//! Pure decision logic, `TelegramBot` feeds observations and performs switches.
+//! In `auto` mode bot prefers webhook, falls back to polling when webhook can not be set
+//! or telegram reports delivery errors, and retries webhook from time to time.
+
+use std::str::FromStr;
+use tokio::time::Duration;
+
+/// configured mode (UPDATE_MODE)
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum UpdateMode {
+    Webhook,
+    Polling,
+    Auto,
+}
+impl FromStr for UpdateMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "webhook" => Ok(UpdateMode::Webhook),
+            "polling" => Ok(UpdateMode::Polling),
+            "auto" => Ok(UpdateMode::Auto),
+            _ => Err(format!("unknown update mode \"{value}\", expected webhook, polling or auto")),
+        }
+    }
+}
+
+/// how updates are received right now
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ModeState {
+    /// nothing set up yet (or last switch failed)
+    Stopped,
+    Webhook,
+    Polling,
+}
+
+/// what controller learned since last decision
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Observation {
+    Startup,
+    /// setWebhook failed
+    WebhookSetFailed,
+    /// getWebhookInfo has no fresh delivery errors
+    WebhookHealthy,
+    /// telegram can not deliver updates to webhook url
+    WebhookFailing,
+    /// deleteWebhook failed, poller was not started
+    PollingStartFailed,
+    /// polling for a while, time to try webhook again
+    RetryTimer,
+    /// poller stopped by error (rejected token, database error), updates are not received
+    PollerFailed,
+}
+
+/// state controller should switch to
+pub fn next_state(mode: UpdateMode, current: ModeState, observation: Observation) -> ModeState {
+    match mode {
+        UpdateMode::Webhook => ModeState::Webhook,
+        UpdateMode::Polling => ModeState::Polling,
+        UpdateMode::Auto => match observation {
+            Observation::Startup | Observation::PollingStartFailed => ModeState::Webhook,
+            Observation::WebhookSetFailed | Observation::WebhookFailing | Observation::PollerFailed =>
+                ModeState::Polling,
+            Observation::WebhookHealthy => current,
+            Observation::RetryTimer => match current {
+                ModeState::Polling => ModeState::Webhook,
+                state => state,
+            },
+        },
+    }
+}
+
+const FIRST_RETRY_DELAY_SECS: u64 = 5;
+const MAX_RETRY_DELAY_SECS: u64 = 600;
+/// auto mode checks webhook delivery errors with this period
+pub const WEBHOOK_CHECK_SECS: u64 = 60;
+/// auto mode retries webhook after polling this long (doubled after every failed try)
+const WEBHOOK_RETRY_SECS: u64 = 600;
+const MAX_WEBHOOK_RETRY_SECS: u64 = 6 * 3600;
+
+/// time until next observation, `failures` is count of failed switches in a row
+pub fn check_delay(mode: UpdateMode, state: ModeState, failures: u32) -> Duration {
+    let secs = match (mode, state) {
+        (_, ModeState::Stopped) =>
+            (FIRST_RETRY_DELAY_SECS << failures.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY_SECS),
+        (UpdateMode::Auto, ModeState::Webhook) => WEBHOOK_CHECK_SECS,
+        (UpdateMode::Auto, ModeState::Polling) =>
+            (WEBHOOK_RETRY_SECS << failures.min(16)).min(MAX_WEBHOOK_RETRY_SECS),
+        // fixed mode, nothing to check
+        _ => 3600,
+    };
+    Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!("webhook".parse::<UpdateMode>(), Ok(UpdateMode::Webhook));
+        assert_eq!(" Polling ".parse::<UpdateMode>(), Ok(UpdateMode::Polling));
+        assert_eq!("auto".parse::<UpdateMode>(), Ok(UpdateMode::Auto));
+        assert!("both".parse::<UpdateMode>().is_err());
+    }
+
+    #[test]
+    fn test_fixed_modes_never_switch() {
+        let observations = [
+            Observation::Startup, Observation::WebhookSetFailed, Observation::WebhookHealthy,
+            Observation::WebhookFailing, Observation::PollingStartFailed, Observation::RetryTimer,
+            Observation::PollerFailed,
+        ];
+        for state in [ModeState::Stopped, ModeState::Webhook, ModeState::Polling] {
+            for observation in observations {
+                assert_eq!(next_state(UpdateMode::Webhook, state, observation), ModeState::Webhook);
+                assert_eq!(next_state(UpdateMode::Polling, state, observation), ModeState::Polling);
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_mode() {
+        use ModeState::*;
+        let auto = UpdateMode::Auto;
+        assert_eq!(next_state(auto, Stopped, Observation::Startup), Webhook);
+        assert_eq!(next_state(auto, Stopped, Observation::WebhookSetFailed), Polling);
+        assert_eq!(next_state(auto, Webhook, Observation::WebhookHealthy), Webhook);
+        assert_eq!(next_state(auto, Webhook, Observation::WebhookFailing), Polling);
+        // no flapping: busy or idle polling does not switch back until retry timer
+        assert_eq!(next_state(auto, Polling, Observation::WebhookHealthy), Polling);
+        assert_eq!(next_state(auto, Polling, Observation::RetryTimer), Webhook);
+        assert_eq!(next_state(auto, Webhook, Observation::RetryTimer), Webhook);
+        assert_eq!(next_state(auto, Stopped, Observation::PollingStartFailed), Webhook);
+        assert_eq!(next_state(auto, Stopped, Observation::PollerFailed), Polling);
+    }
+
+    #[test]
+    fn test_check_delay() {
+        let auto = UpdateMode::Auto;
+        assert_eq!(check_delay(auto, ModeState::Stopped, 1), Duration::from_secs(5));
+        assert_eq!(check_delay(auto, ModeState::Stopped, 3), Duration::from_secs(20));
+        assert_eq!(check_delay(auto, ModeState::Stopped, 100), Duration::from_secs(MAX_RETRY_DELAY_SECS));
+        assert_eq!(check_delay(auto, ModeState::Webhook, 0), Duration::from_secs(WEBHOOK_CHECK_SECS));
+        assert_eq!(check_delay(auto, ModeState::Polling, 0), Duration::from_secs(600));
+        assert_eq!(check_delay(auto, ModeState::Polling, 1), Duration::from_secs(1200));
+        assert_eq!(check_delay(auto, ModeState::Polling, 30), Duration::from_secs(MAX_WEBHOOK_RETRY_SECS));
+    }
+}
@@ -4,72 +4,137 @@
  */
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::watch;
 use tokio::time::{sleep, Duration};
 use base64::Engine;
 use crate::error::BotError;
-use crate::{db, random};
-use crate::telegram_bot::{ api_type, TelegramBot };
+use crate::{db, metrics, random};
+use crate::telegram_bot::{ api_type, dispatcher, TelegramBot };
 
-pub async fn poll_updates() -> Result<(), BotError> {
-    println!("Telegram poller started");
-    let mut next_update_id = 0;
-    let mut empty_poll_count = 0;
+/// bot_state key of next update id for getUpdates
+const UPDATE_OFFSET_KEY: &str = "update_offset";
+/// unix time of last getUpdates response
+static LAST_POLL: AtomicI64 = AtomicI64::new(0);
+
+pub fn last_poll_time() -> i64 {
+    LAST_POLL.load(Ordering::Relaxed)
+}
+
+/// receive updates until `stop` is set. Before return handles updates telegram already has
+/// and confirms them, so webhook set after poller stopped gets only new updates
+pub async fn poll_updates(mut stop: watch::Receiver<bool>) -> Result<(), BotError> {
+    let mut next_update_id: i64 = match db::get_bot_state(UPDATE_OFFSET_KEY).await? {
+        None => 0,
+        Some(offset) => offset.parse().unwrap_or(0),
+    };
+    tracing::info!("Telegram poller started with offset {next_update_id}");
+    LAST_POLL.store(db::unix_time_current(), Ordering::Relaxed);
     loop {
-        let updates = match TelegramBot::get_updates(next_update_id).await {
-            Ok(updates) => { updates },
+        let result = tokio::select! {
+            // in-flight getUpdates is dropped, updates from it are not confirmed and will be
+            // received again
+            _ = stop.changed() => break,
+            result = TelegramBot::get_updates(next_update_id, TelegramBot::poll_timeout()) => result,
+        };
+        let updates = match result {
+            Ok(updates) => {
+                LAST_POLL.store(db::unix_time_current(), Ordering::Relaxed);
+                updates
+            },
+            Err(err) if err.is_unauthorized() => {
+                tracing::error!("TelegramBot::get_updates() bot token rejected, stop polling: {err:?}");
+                return Err(err);
+            },
+            Err(BotError::TelegramApi { retry_after: Some(retry_after), .. }) => {
+                if sleep_or_stop(&mut stop, Duration::from_secs(retry_after)).await {
+                    break;
+                }
+                continue;
+            },
             Err(err) => {
+                // 409 conflict: webhook set or other instance polling, mode controller decides
                 tracing::error!("TelegramBot::get_updates() got error {err:?}");
-                sleep(Duration::from_millis(1000)).await;
+                if sleep_or_stop(&mut stop, Duration::from_millis(1000)).await {
+                    break;
+                }
                 continue;
             },
         };
-        if updates.len() == 0 {
-            empty_poll_count += 1;
-            if empty_poll_count >= 3 {
-                break;
-            }
-        } else {
-            empty_poll_count = 0;
+        if !updates.is_empty() {
             next_update_id = handle_updates(updates).await?;
         }
     }
-    TelegramBot::set_mode_webhook().await?;
 
-    return Ok(());
+    loop {
+        let updates = TelegramBot::get_updates(next_update_id, 0).await?;
+        if updates.is_empty() {
+            break;
+        }
+        next_update_id = handle_updates(updates).await?;
+    }
+    tracing::info!("Telegram poller stopped");
+
+    Ok(())
 }
+/// returns true if stop requested while sleeping
+async fn sleep_or_stop(stop: &mut watch::Receiver<bool>, duration: Duration) -> bool {
+    tokio::select! {
+        _ = stop.changed() => true,
+        _ = sleep(duration) => false,
+    }
+}
+/// process updates by worker pool and wait for them, returns offset for next getUpdates
+/// (saved in db, so restarted poller does not get handled updates again)
 pub async fn handle_updates(updates: Vec<api_type::ApiUpdate>) -> Result<i64, BotError> {
-    let mut next_update_id = 0;
+    let next_update_id = updates.iter().map(|update| update.update_id + 1).max().unwrap_or(0);
+    dispatcher::dispatch_and_wait(updates).await?;
+    db::set_bot_state(UPDATE_OFFSET_KEY, &next_update_id.to_string()).await?;
 
-    for update in updates {
-        let update_id = update.update_id;
-        if update_id >= next_update_id {
-            next_update_id = update_id + 1;
-        }
-        handle_update(update).await?;
+    Ok(next_update_id)
+}
+
+/// handles every update once, no matter if it came by webhook or getUpdates
+pub async fn handle_update( update: ApiUpdate ) -> Result<(),BotError> {
+    let started = std::time::Instant::now();
+    let result = handle_update_unmeasured(update).await;
+    let result_label = match &result {
+        Ok(true) => "processed",
+        Ok(false) => "duplicate",
+        Err(_) => "error",
+    };
+    metrics::inc_counter(metrics::UPDATES, &[("result", result_label)]);
+    if result_label != "duplicate" {
+        metrics::observe(metrics::UPDATE_DURATION, &[], started.elapsed().as_secs_f64());
     }
 
-    return Ok(next_update_id);
+    result.map(|_| ())
 }
 
-pub async fn handle_update( update: ApiUpdate ) -> Result<(),BotError> {
+/// false if update was already processed
+async fn handle_update_unmeasured( update: ApiUpdate ) -> Result<bool,BotError> {
+    if !db::claim_update(update.update_id).await? {
+        tracing::debug!("skip update, already processed");
+        return Ok(false);
+    }
+
     let message = match update.message {
-        None => return Ok(()),
+        None => return Ok(true),
         Some(message) => message
     };
     let chat_id = message.chat.id;
     let text = match message.text {
-        None => return Ok(()),
+        None => return Ok(true),
         Some(text) => text
     };
     if let Err(err) = handle_message(chat_id, &text).await {
-        tracing::error!("handle_message() got error processing chat {chat_id},with text\n'{text}'\n{err:?}");
+        tracing::error!("handle_message() failed: {err:?}");
     }
 
-    Ok(())
+    Ok(true)
 }
 
 async fn handle_message(chat_id: i64, text: &str) -> Result<(),BotError> {
-    println!("processing text from chat_id {chat_id} \"{text}\"");
     let text = text.trim();
     if text.starts_with("/") {
         match extract_command(text) {
@@ -81,12 +146,12 @@ async fn handle_message(chat_id: i64, text: &str) -> Result<(),BotError> {
         };
     }
 
-    return Ok(());
+    Ok(())
 }
 fn extract_command(text: &str) -> Option<(Command,&str)>{
     let text_pair: Vec<&str> = text.splitn(2, &[' ', '\t']).collect();
     // let r: Vec<&str> = text.splitn(2, |c| {c==' '||c=='\t'}).collect();
-    if text_pair.len() < 1 {
+    if text_pair.is_empty() {
         return None;
     }
     let cmd_text = text_pair[0];
@@ -98,41 +163,73 @@ fn extract_command(text: &str) -> Option<(Command,&str)>{
         return Some((command,text_pair[1].trim()));
     }
 
-    return Some((command,""));
+    Some((command,""))
 }
 async fn handle_command(chat_id: i64, command: Command, tail: &str) -> Result<(),BotError> {
     match command {
         Command::Start => handle_start(chat_id, tail).await?,
         Command::Stop => handle_stop(chat_id).await?,
         Command::Help => handle_help(chat_id).await?,
-        Command::ShowToken => handle_show_token(chat_id).await?,
-        Command::UpdateToken => handle_update_token(chat_id).await?,
+        Command::ShowToken => handle_show_token(chat_id, tail).await?,
+        Command::UpdateToken => handle_update_token(chat_id, tail).await?,
+        Command::NewToken => handle_new_token(chat_id, tail).await?,
+        Command::Tokens => handle_tokens(chat_id).await?,
+        Command::RevokeToken => handle_revoke_token(chat_id, tail).await?,
+        Command::IntegrationSecret => handle_integration_secret(chat_id, tail).await?,
     }
 
-    return Ok(());
+    Ok(())
+}
+
+/// label of token created by /start
+const DEFAULT_LABEL: &str = "default";
+const MAX_LABEL_LEN: usize = 32;
+
+/// token label from command argument, `None` if label is not valid
+fn parse_label(tail: &str) -> Option<&str> {
+    let label = if tail.is_empty() { DEFAULT_LABEL } else { tail };
+    if label.len() > MAX_LABEL_LEN
+        || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return None;
+    }
+    Some(label)
+}
+async fn send_bad_label(chat_id: i64) -> Result<(),BotError> {
+    let response_message = format!(
+        "Token label should be up to {MAX_LABEL_LEN} latin letters, digits, '-', '_' or '.', \
+        for example: ci, backup-cron, website-form"
+    );
+    TelegramBot::send_message(chat_id, &response_message).await?;
+    Ok(())
+}
+/// create token with label and send it to chat
+async fn create_token(chat_id: i64, label: &str, header: &str) -> Result<(),BotError> {
+    let mut token: [u8; 32] = [0; 32];
+    random::gen_random(&mut token[..])?;
+    db::create_session(&token, chat_id, label).await?;
+    let token_str = base64::engine::general_purpose::STANDARD_NO_PAD.encode(token);
+    let response_message = format!(
+        "{header} \"{label}\"\n\n\
+        {token_str}\n\n\
+        use it to send requests using notify-me-api npm package. \
+        Save it now: bot stores only token hash and can not show it again."
+    );
+    TelegramBot::send_message(chat_id, &response_message).await?;
+    Ok(())
 }
 
 async fn handle_start(chat_id: i64, tail: &str) -> Result<(),BotError> {
-    println!("/start handler for chat {chat_id} with tail \"{tail}\"");
-    match db::find_token_by_chat(chat_id).await? {
+    tracing::debug!("/start command with tail \"{tail}\"");
+    match db::find_session_by_label(chat_id, DEFAULT_LABEL).await? {
         None => {
-            let mut token: [u8; 32] = [0; 32];
-            random::gen_random(&mut token[..])?;
-            db::create_session(&token, chat_id).await?;
-            let token_str = base64::engine::general_purpose::STANDARD_NO_PAD.encode(&token);
-            let response_message = format!(
-                "generated token\n\n\
-                {token_str}\n\n\
-                use it to send requests using notify-me-api npm package"
-            );
-            TelegramBot::send_message(chat_id, &response_message).await?;
+            create_token(chat_id, DEFAULT_LABEL, "generated token").await?;
         },
-        Some(token) => {
-            let token_str = base64::engine::general_purpose::STANDARD_NO_PAD.encode(&token);
+        Some(session) => {
             let response_message = format!(
-                "token already exist for your chat\n\n\
-                {token_str}\n\n\
-                use it to send requests using notify-me-api npm package"
+                "token already exist for your chat: {}...\n\n\
+                If you lost it, get new one with /update_token",
+                session.token_prefix
             );
             TelegramBot::send_message(chat_id, &response_message).await?;
         }
@@ -140,18 +237,16 @@ async fn handle_start(chat_id: i64, tail: &str) -> Result<(),BotError> {
     Ok(())
 }
 async fn handle_stop( chat_id: i64 ) -> Result<(),BotError> {
-    println!("/stop handler for chat {chat_id}");
+    tracing::debug!("/stop command");
     db::delete_session(chat_id).await?;
 
-    let response_message = format!(
-        "Deleted token for this chat.\n\n\
-        You will not receive any messages from this bot until next /start."
-    );
-    TelegramBot::send_message(chat_id, &response_message).await?;
+    let response_message = "Deleted all tokens for this chat.\n\n\
+        You will not receive any messages from this bot until next /start.";
+    TelegramBot::send_message(chat_id, response_message).await?;
     Ok(())
 }
 async fn handle_help( chat_id: i64 ) -> Result<(),BotError> {
-    println!("/help handler for chat {chat_id}");
+    tracing::debug!("/help command");
 
     let mut cmd_list_message = String::new();
     for cmd in CMD_LIST {
@@ -160,29 +255,35 @@ async fn handle_help( chat_id: i64 ) -> Result<(),BotError> {
     let response_message = format!(
         "This bot allow to send messages from web to telegram chats.\n\
         First connect to this bot (/start) and get token. \
-        Use it to send requests using notify-me-api npm package\n\n\
+        Use it to send requests using notify-me-api npm package\n\
+        Create separate token for every integration with /new_token <label>, \
+        so one of them can be rotated or revoked without breaking others.\n\n\
         Available commands:\n{cmd_list_message}"
     );
     TelegramBot::send_message(chat_id, &response_message).await?;
     Ok(())
 }
-async fn handle_show_token( chat_id: i64 ) -> Result<(),BotError> {
-    println!("/show_token handler for chat {chat_id}");
+async fn handle_show_token( chat_id: i64, tail: &str ) -> Result<(),BotError> {
+    tracing::debug!("/show_token command");
+    let label = match parse_label(tail) {
+        None => return send_bad_label(chat_id).await,
+        Some(label) => label,
+    };
 
-    match db::find_token_by_chat(chat_id).await? {
+    match db::find_session_by_label(chat_id, label).await? {
         None => {
             let response_message = format!(
-                "Token not found, this chat not connected to bot.\n\n\
-                run /start to connect and get token."
+                "Token \"{label}\" not found.\n\n\
+                run /start to connect and get token or /tokens to list tokens of this chat."
             );
             TelegramBot::send_message(chat_id, &response_message).await?;
         }
-        Some(token) => {
-            let token_str = base64::engine::general_purpose::STANDARD_NO_PAD.encode(&token);
+        Some(session) => {
             let response_message = format!(
-                "your token:\n\n\
-                {token_str}\n\n\
-                use it to send requests using notify-me-api npm package"
+                "your token \"{label}\" starts with {}... (created {})\n\n\
+                Full token is shown only once when created. \
+                If you lost it, get new one with /update_token {label}",
+                session.token_prefix, db::format_unix_date(session.created_at)
             );
             TelegramBot::send_message(chat_id, &response_message).await?;
         }
@@ -190,21 +291,110 @@ async fn handle_show_token( chat_id: i64 ) -> Result<(),BotError> {
 
     Ok(())
 }
-async fn handle_update_token( chat_id: i64 ) -> Result<(),BotError>
+async fn handle_update_token( chat_id: i64, tail: &str ) -> Result<(),BotError>
 {
-    println!("/start handler for chat {chat_id}");
-    let mut token: [u8; 32] = [0; 32];
-    random::gen_random(&mut token[..])?;
-    db::create_session(&token, chat_id).await?;
-    let token_str = base64::engine::general_purpose::STANDARD_NO_PAD.encode(&token);
-    let response_message = format!(
-        "new token\n\n\
-        {token_str}\n\n\
-        use it to send requests using notify-me-api npm package"
-    );
+    tracing::debug!("/update_token command");
+    let label = match parse_label(tail) {
+        None => return send_bad_label(chat_id).await,
+        Some(label) => label,
+    };
+    if label != DEFAULT_LABEL && db::find_session_by_label(chat_id, label).await?.is_none() {
+        let response_message = format!(
+            "Token \"{label}\" not found, create it with /new_token {label}"
+        );
+        TelegramBot::send_message(chat_id, &response_message).await?;
+        return Ok(());
+    }
+    create_token(chat_id, label, "new token").await?;
+
+    Ok(())
+}
+async fn handle_new_token( chat_id: i64, tail: &str ) -> Result<(),BotError>
+{
+    tracing::debug!("/new_token command");
+    if tail.is_empty() {
+        TelegramBot::send_message(chat_id, "usage: /new_token <label>, for example /new_token ci").await?;
+        return Ok(());
+    }
+    let label = match parse_label(tail) {
+        None => return send_bad_label(chat_id).await,
+        Some(label) => label,
+    };
+    if db::find_session_by_label(chat_id, label).await?.is_some() {
+        let response_message = format!(
+            "Token \"{label}\" already exist, rotate it with /update_token {label} \
+            or choose another label"
+        );
+        TelegramBot::send_message(chat_id, &response_message).await?;
+        return Ok(());
+    }
+    create_token(chat_id, label, "generated token").await?;
+
+    Ok(())
+}
+async fn handle_tokens( chat_id: i64 ) -> Result<(),BotError>
+{
+    tracing::debug!("/tokens command");
+    let sessions = db::list_sessions(chat_id).await?;
+    if sessions.is_empty() {
+        TelegramBot::send_message(chat_id, "This chat has no tokens, run /start to get one.").await?;
+        return Ok(());
+    }
+    let mut response_message = String::from("tokens of this chat:\n\n");
+    for session in sessions {
+        response_message.push_str(&format!(
+            "{} {}... (created {})\n",
+            session.label, session.token_prefix, db::format_unix_date(session.created_at)));
+    }
+    TelegramBot::send_message(chat_id, &response_message).await?;
+
+    Ok(())
+}
+async fn handle_revoke_token( chat_id: i64, tail: &str ) -> Result<(),BotError>
+{
+    tracing::debug!("/revoke_token command");
+    if tail.is_empty() {
+        TelegramBot::send_message(chat_id, "usage: /revoke_token <label>, see /tokens for labels").await?;
+        return Ok(());
+    }
+    let label = match parse_label(tail) {
+        None => return send_bad_label(chat_id).await,
+        Some(label) => label,
+    };
+    let response_message = if db::delete_session_by_label(chat_id, label).await? {
+        format!("Token \"{label}\" revoked, other tokens still work.")
+    } else {
+        format!("Token \"{label}\" not found, see /tokens")
+    };
     TelegramBot::send_message(chat_id, &response_message).await?;
 
-    return Ok(());
+    Ok(())
+}
+
+async fn handle_integration_secret( chat_id: i64, tail: &str ) -> Result<(),BotError>
+{
+    tracing::debug!("/integration_secret command");
+    let label = match parse_label(tail) {
+        None => return send_bad_label(chat_id).await,
+        Some(label) => label,
+    };
+    let mut secret: [u8; 20] = [0; 20];
+    random::gen_random(&mut secret[..])?;
+    let secret = hex::encode(secret);
+    let response_message = if db::set_integration_secret(chat_id, label, &secret).await? {
+        format!(
+            "webhook secret for token \"{label}\"\n\n\
+            {secret}\n\n\
+            set it as Secret of GitHub webhook (url .../integrations/github/<token>) \
+            or Secret token of GitLab webhook (url .../integrations/gitlab/<token>). \
+            Previous secret of this token stops working."
+        )
+    } else {
+        format!("Token \"{label}\" not found, see /tokens")
+    };
+    TelegramBot::send_message(chat_id, &response_message).await?;
+
+    Ok(())
 }
 
 #[derive(Debug,Clone)]
@@ -214,6 +404,10 @@ enum Command {
     Help,
     ShowToken,
     UpdateToken,
+    NewToken,
+    Tokens,
+    RevokeToken,
+    IntegrationSecret,
 }
 #[derive(Debug)]
 pub struct TelegramCommand {
@@ -225,12 +419,20 @@ pub static CMD_LIST: &[TelegramCommand] = &[ // FIXME: combine this list and enu
     TelegramCommand{ name: "/start", command: Command::Start
         , description: "Start chat (connect to bot)" },
     TelegramCommand{ name: "/stop", command: Command::Stop
-        , description: "Stop chat (disconnect from bot)"},
+        , description: "Stop chat (disconnect from bot, revoke all tokens)"},
     TelegramCommand{ name: "/help", command: Command::Help, description: "Show commands"},
     TelegramCommand{ name: "/show_token", command: Command::ShowToken
-        , description: "Show my current token"},
+        , description: "Show my current token (/show_token <label> for named one)"},
     TelegramCommand{ name: "/update_token", command: Command::UpdateToken
-        , description: "Update current token"},
+        , description: "Update current token (/update_token <label> for named one)"},
+    TelegramCommand{ name: "/new_token", command: Command::NewToken
+        , description: "Create named token: /new_token <label>"},
+    TelegramCommand{ name: "/tokens", command: Command::Tokens
+        , description: "List tokens of this chat"},
+    TelegramCommand{ name: "/revoke_token", command: Command::RevokeToken
+        , description: "Revoke named token: /revoke_token <label>"},
+    TelegramCommand{ name: "/integration_secret", command: Command::IntegrationSecret
+        , description: "New secret for GitHub/GitLab webhooks (/integration_secret <label> for named token)"},
 ];
 
 use once_cell::sync::OnceCell;
@@ -245,13 +447,15 @@ fn cmd_map() -> &'static HashMap<&'static str,&'static TelegramCommand>
 
 pub fn init() -> Result<(), BotError>
 {
-    let mut cmd_map: HashMap<&str,&TelegramCommand> = HashMap::new();
-    for cmd in CMD_LIST {
-        cmd_map.insert(cmd.name, cmd);
-    }
-    CMD_MAP.set(cmd_map).unwrap();
+    CMD_MAP.get_or_init(|| {
+        let mut cmd_map: HashMap<&str,&TelegramCommand> = HashMap::new();
+        for cmd in CMD_LIST {
+            cmd_map.insert(cmd.name, cmd);
+        }
+        cmd_map
+    });
 
-    return Ok(());
+    Ok(())
 }
 
 #[cfg(test)]
@@ -260,7 +464,7 @@ mod tests {
 
     #[test]
     fn test_extract_command() {
-        init();
+        let _ = init();
         extract_command("");
         extract_command("/");
         extract_command("/ ");
@@ -268,4 +472,143 @@ mod tests {
         extract_command("/\t 1");
         assert_eq!(2+2, 4);
     }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label(""), Some(DEFAULT_LABEL));
+        assert_eq!(parse_label("backup-cron"), Some("backup-cron"));
+        assert_eq!(parse_label("with space"), None);
+        assert_eq!(parse_label(&"x".repeat(MAX_LABEL_LEN + 1)), None);
+    }
+
+    #[test]
+    fn test_format_unix_date() {
+        assert_eq!(db::format_unix_date(0), "1970-01-01");
+        assert_eq!(db::format_unix_date(1709251199), "2024-02-29");
+        assert_eq!(db::format_unix_date(1792108800), "2026-10-16");
+    }
+
+    /// tests switching update mode of shared bot run one at a time
+    static MODE_SWITCH: once_cell::sync::Lazy<tokio::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(|| tokio::sync::Mutex::new(()));
+
+    #[test]
+    fn test_polling_handover() {
+        use crate::telegram_bot::{bot, update_mode::ModeState};
+        let env = crate::integration_tests::env();
+        crate::integration_tests::RUNTIME.block_on(async {
+            let _mode_switch = MODE_SWITCH.lock().await;
+            let chat_id = 202;
+            bot().switch_mode(ModeState::Polling).await.unwrap();
+            // repeated switch keeps the same poller
+            bot().switch_mode(ModeState::Polling).await.unwrap();
+            env.mock.push_update(serde_json::json!({
+                "update_id": 5000,
+                "message": {"message_id": 1, "date": 0, "chat": {"id": chat_id}, "text": "/help"}
+            }));
+            for _ in 0..100 {
+                if !env.mock.sent_texts(chat_id).is_empty() {
+                    break;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(env.mock.sent_texts(chat_id).len(), 1);
+
+            let set_webhook_count = env.mock.calls("setWebhook").len();
+            bot().switch_mode(ModeState::Webhook).await.unwrap();
+            let control = bot().mode_control.lock().await;
+            assert!(control.poller.is_none());
+            assert_eq!(control.state, ModeState::Webhook);
+            assert_eq!(env.mock.calls("setWebhook").len(), set_webhook_count + 1);
+            // handled update confirmed by poller before webhook set
+            let last_poll = env.mock.calls("getUpdates").pop().unwrap();
+            assert_eq!(last_poll["offset"], 5001);
+            assert_eq!(last_poll["timeout"], 0);
+            assert_eq!(db::get_bot_state(UPDATE_OFFSET_KEY).await.unwrap(), Some("5001".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_skip_processed_update() {
+        let env = crate::integration_tests::env();
+        crate::integration_tests::RUNTIME.block_on(async {
+            let chat_id = 203;
+            let update = || serde_json::from_value::<ApiUpdate>(serde_json::json!({
+                "update_id": 6000,
+                "message": {"message_id": 1, "date": 0, "chat": {"id": chat_id}, "text": "/update_token"}
+            })).unwrap();
+            handle_update(update()).await.unwrap();
+            // same update again (webhook retry, poller restart): token is not rotated twice
+            handle_update(update()).await.unwrap();
+            assert_eq!(env.mock.sent_texts(chat_id).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_failed_poller_restarted() {
+        use crate::telegram_bot::{bot, mock_api::MockError, update_mode::ModeState};
+        let env = crate::integration_tests::env();
+        crate::integration_tests::RUNTIME.block_on(async {
+            let _mode_switch = MODE_SWITCH.lock().await;
+            let chat_id = 205;
+            bot().switch_mode(ModeState::Polling).await.unwrap();
+            // rejected token stops poller
+            env.mock.fail_method("getUpdates", 1, MockError {
+                code: 401, description: "Unauthorized".to_string(), retry_after: None
+            });
+            env.mock.push_update(serde_json::json!({
+                "update_id": 8000,
+                "message": {"message_id": 1, "date": 0, "chat": {"id": chat_id}, "text": "/help"}
+            }));
+            // controller restarts poller after retry delay
+            for _ in 0..200 {
+                if !env.mock.sent_texts(chat_id).is_empty() {
+                    break;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(env.mock.sent_texts(chat_id).len(), 1);
+            assert_eq!(bot().mode_control.lock().await.state, ModeState::Polling);
+
+            bot().switch_mode(ModeState::Webhook).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_named_tokens() {
+        let env = crate::integration_tests::env();
+        crate::integration_tests::RUNTIME.block_on(async {
+            let chat_id = 201;
+            handle_message(chat_id, "/start").await.unwrap();
+            handle_message(chat_id, "/new_token ci").await.unwrap();
+            handle_message(chat_id, "/new_token backup-cron").await.unwrap();
+            let labels: Vec<String> = db::list_sessions(chat_id).await.unwrap()
+                .into_iter().map(|session| session.label).collect();
+            assert_eq!(labels.len(), 3);
+
+            // token is sent once in message with its label: "generated token \"ci\"\n\n<token>\n\n..."
+            let ci_message = env.mock.sent_texts(chat_id).into_iter()
+                .find(|text| text.starts_with("generated token \"ci\"")).unwrap();
+            let ci_token_str = ci_message.split("\n\n").nth(1).unwrap().to_string();
+            let ci_token = base64::engine::general_purpose::STANDARD_NO_PAD.decode(&ci_token_str).unwrap();
+            handle_message(chat_id, "/update_token backup-cron").await.unwrap();
+            handle_message(chat_id, "/revoke_token default").await.unwrap();
+            // rotating and revoking other tokens does not break "ci"
+            assert_eq!(db::find_chat_by_token(&ci_token).await.unwrap(), Some(chat_id));
+            assert!(db::find_session_by_label(chat_id, DEFAULT_LABEL).await.unwrap().is_none());
+
+            handle_message(chat_id, "/show_token ci").await.unwrap();
+            let texts = env.mock.sent_texts(chat_id);
+            let show_token = texts.last().unwrap();
+            assert!(show_token.contains(&format!("{}...", &ci_token_str[..6])));
+            assert!(!show_token.contains(&ci_token_str));
+
+            handle_message(chat_id, "/tokens").await.unwrap();
+            let texts = env.mock.sent_texts(chat_id);
+            let tokens_list = texts.last().unwrap();
+            assert!(tokens_list.contains("ci "));
+            assert!(tokens_list.contains("backup-cron "));
+            assert!(!tokens_list.contains("default"));
+        });
+    }
 }
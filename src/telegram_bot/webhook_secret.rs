@@ -0,0 +1,152 @@
+/*
+ * Copyright 2023 Alex Syrnikov <alex.syrnikov19@gmail.com>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Secret token telegram sends in X-Telegram-Bot-Api-Secret-Token header.
+//!
+//! Secret is stored in bot_state, so updates sent to webhook during restart are accepted.
+//! It can be set with TELEGRAM_WEBHOOK_SECRET, after change of configured secret the previous
+//! one is accepted for a while (deliveries already in flight carry old secret).
+
+use base64::Engine;
+use base64::engine::general_purpose::NO_PAD;
+
+use crate::error::BotError;
+use crate::{db, random};
+
+const SECRET_KEY: &str = "webhook_secret";
+const PREVIOUS_SECRET_KEY: &str = "webhook_secret_previous";
+const PREVIOUS_UNTIL_KEY: &str = "webhook_secret_previous_until";
+/// old secret accepted this long after rotation
+pub const ROTATION_OVERLAP_SECS: i64 = 3600;
+
+#[derive(Debug)]
+pub struct WebhookSecret {
+    pub current: String,
+    previous: Option<String>,
+    /// unix time previous secret expires
+    previous_until: i64,
+}
+
+impl WebhookSecret {
+    pub fn is_valid(&self, token: &str, now: i64) -> bool {
+        if secret_equal(token, &self.current) {
+            return true;
+        }
+        match &self.previous {
+            Some(previous) => now < self.previous_until && secret_equal(token, previous),
+            None => false,
+        }
+    }
+}
+
+fn secret_equal(a: &str, b: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(a.as_bytes(), b.as_bytes()).is_ok()
+}
+
+/// telegram allows 1-256 characters A-Z, a-z, 0-9, _ and -
+pub fn is_valid_format(secret: &str) -> bool {
+    !secret.is_empty() && secret.len() <= 256
+        && secret.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn generate() -> Result<String, BotError> {
+    let mut secret_buf: [u8; 32] = [0; 32];
+    random::gen_random(&mut secret_buf[..])?;
+
+    let token_alphabet =
+        base64::alphabet::Alphabet::new("-_ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789")
+            .unwrap();
+    let token_engine = base64::engine::GeneralPurpose::new(&token_alphabet, NO_PAD);
+
+    Ok(token_engine.encode(secret_buf))
+}
+
+/// secret from db, `configured` secret (format checked by config) replaces stored one
+/// (with overlap window)
+pub async fn load(configured: Option<&str>) -> Result<WebhookSecret, BotError> {
+    let stored = db::get_bot_state(SECRET_KEY).await?;
+    match (configured, stored) {
+        (Some(configured), Some(stored)) if configured != stored => {
+            tracing::info!("webhook secret changed, previous one accepted for {ROTATION_OVERLAP_SECS}s");
+            return rotate(configured, Some(stored)).await;
+        },
+        (None, None) => return rotate(&generate()?, None).await,
+        (Some(configured), None) => return rotate(configured, None).await,
+        _ => {},
+    }
+
+    let current = db::get_bot_state(SECRET_KEY).await?.unwrap_or_default();
+    let previous = db::get_bot_state(PREVIOUS_SECRET_KEY).await?;
+    let previous_until = db::get_bot_state(PREVIOUS_UNTIL_KEY).await?
+        .and_then(|until| until.parse().ok())
+        .unwrap_or(0);
+
+    Ok(WebhookSecret { current, previous, previous_until })
+}
+
+async fn rotate(secret: &str, previous: Option<String>) -> Result<WebhookSecret, BotError> {
+    let previous_until = db::unix_time_current() + ROTATION_OVERLAP_SECS;
+    let previous_until_str = previous_until.to_string();
+    let mut values = vec![(SECRET_KEY, secret)];
+    if let Some(previous) = &previous {
+        values.push((PREVIOUS_SECRET_KEY, previous));
+        values.push((PREVIOUS_UNTIL_KEY, &previous_until_str));
+    }
+    db::set_bot_state_values(&values).await?;
+
+    Ok(WebhookSecret { current: secret.to_string(), previous, previous_until })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlap_window() {
+        let secret = WebhookSecret {
+            current: "new".to_string(),
+            previous: Some("old".to_string()),
+            previous_until: 1000,
+        };
+        assert!(secret.is_valid("new", 999));
+        assert!(secret.is_valid("old", 999));
+        assert!(!secret.is_valid("old", 1000));
+        assert!(secret.is_valid("new", 5000));
+        assert!(!secret.is_valid("", 0));
+        assert!(!secret.is_valid("ne", 0));
+    }
+
+    #[test]
+    fn test_load_reuses_and_rotates_stored_secret() {
+        let _env = crate::integration_tests::env();
+        crate::integration_tests::RUNTIME.block_on(async {
+            // secret generated at bot start survives restart
+            let generated = load(None).await.unwrap();
+            assert!(is_valid_format(&generated.current));
+            assert_eq!(load(None).await.unwrap().current, generated.current);
+
+            let now = db::unix_time_current();
+            let rotated = load(Some("configured-secret")).await.unwrap();
+            assert_eq!(rotated.current, "configured-secret");
+            assert!(rotated.is_valid(&generated.current, now));
+
+            // restart with same configured secret keeps overlap window
+            let restarted = load(Some("configured-secret")).await.unwrap();
+            assert!(restarted.is_valid("configured-secret", now));
+            assert!(restarted.is_valid(&generated.current, now));
+            assert!(!restarted.is_valid(&generated.current, now + ROTATION_OVERLAP_SECS + 1));
+        });
+    }
+
+    #[test]
+    fn test_format() {
+        assert!(is_valid_format("abc-DEF_123"));
+        assert!(!is_valid_format(""));
+        assert!(!is_valid_format("with space"));
+        assert!(!is_valid_format(&"a".repeat(257)));
+        random::init().unwrap();
+        assert!(is_valid_format(&generate().unwrap()));
+    }
+}